@@ -1,12 +1,59 @@
 /// Minimal API based - Only using what we need
 /// https://developer.basespace.illumina.com/docs/content/documentation/rest-api/api-reference
 use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// List endpoints only return one page of items at a time.
+/// Every list response carries the paging envelope, so we
+/// can keep asking for the next offset until we have them all.
+pub trait Paginated {
+    /// Number of items in this page
+    fn len(&self) -> usize;
+
+    /// Total number of items available on the server, if it said
+    fn total_count(&self) -> Option<usize>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether this is the last page, once `fetched` items have been
+    /// received in all. Without a TotalCount, only a page shorter than
+    /// the `limit` asked for can tell us there are no more.
+    fn is_last(&self, fetched: usize, limit: usize) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        match self.total_count() {
+            Some(total_count) => fetched >= total_count,
+            None => self.len() < limit,
+        }
+    }
+}
+
+macro_rules! impl_paginated {
+    ($($response:ty),*) => {
+        $(
+            impl Paginated for $response {
+                fn len(&self) -> usize {
+                    self.items.len()
+                }
+
+                fn total_count(&self) -> Option<usize> {
+                    self.total_count
+                }
+            }
+        )*
+    };
+}
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 #[serde(remote = "Self")]
 pub struct ProjectResponse {
     items: Vec<_Project>,
+    #[serde(default)]
+    pub total_count: Option<usize>,
 }
 
 impl ProjectResponse {
@@ -64,6 +111,7 @@ impl AsRef<str> for Project {
 #[serde(remote = "Self")]
 pub struct CurrentUserResponse {
     pub id: String,
+    pub name: String,
 }
 
 #[derive(Deserialize, Debug)]
@@ -78,6 +126,8 @@ pub struct User {
 #[serde(remote = "Self")]
 pub struct FileResponse {
     pub items: Vec<DataFile>,
+    #[serde(default)]
+    pub total_count: Option<usize>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -87,6 +137,25 @@ pub struct DataFile {
     pub name: String,
     pub size: i64,
     pub e_tag: String,
+    /// Location of the file within its run or app result,
+    /// e.g. InterOp/QMetricsOut.bin
+    #[serde(default)]
+    pub path: Option<String>,
+    /// The sample the file was listed from, if any
+    #[serde(skip)]
+    pub sample: Option<Sample>,
+    /// Where to save the file, relative to the output
+    /// directory, when it isn't simply the name
+    #[serde(skip)]
+    pub destination: Option<PathBuf>,
+}
+
+impl DataFile {
+    pub fn destination(&self) -> &Path {
+        self.destination
+            .as_deref()
+            .unwrap_or_else(|| Path::new(&self.name))
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -94,6 +163,8 @@ pub struct DataFile {
 #[serde(remote = "Self")]
 pub struct SampleResponse {
     pub items: Vec<Sample>,
+    #[serde(default)]
+    pub total_count: Option<usize>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -110,20 +181,85 @@ pub struct Sample {
 #[serde(rename_all = "PascalCase")]
 #[serde(remote = "Self")]
 pub struct RunResponse {
-    items: Vec<Run>,
+    items: Vec<_Run>,
+    #[serde(default)]
+    pub total_count: Option<usize>,
+}
+
+impl RunResponse {
+    /// Same as ProjectResponse::projects_as_user, since runs
+    /// can also be shared between accounts.
+    pub fn runs_as_user(self, user_id: &str) -> Vec<Run> {
+        self.items
+            .into_iter()
+            .map(|run| Run {
+                name: run.name,
+                id: run.id,
+                experiment_name: run.experiment_name,
+                flowcell_barcode: run.flowcell_barcode,
+                user_owned_by: run.user_owned_by,
+                user_fetched_by_id: user_id.to_owned(),
+                date_created: run.date_created,
+            })
+            .collect()
+    }
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
+struct _Run {
+    pub name: String,
+    pub id: String,
+    pub experiment_name: Option<String>,
+    pub flowcell_barcode: Option<String>,
+    pub user_owned_by: User,
+    pub date_created: String,
+}
+
+#[derive(Debug)]
 pub struct Run {
     pub name: String,
     pub id: String,
+    pub experiment_name: Option<String>,
+    pub flowcell_barcode: Option<String>,
     pub user_owned_by: User,
+    pub user_fetched_by_id: String,
     pub date_created: String,
 }
 
+impl Run {
+    /// The flowcell barcode if basespace reports it, otherwise the last
+    /// field of a run folder name like 220101_M00001_0001_000000000-ABCDE
+    pub fn flowcell_id(&self) -> Option<&str> {
+        if let Some(barcode) = &self.flowcell_barcode {
+            return Some(barcode);
+        }
+        let fields: Vec<_> = self.name.split('_').collect();
+        if fields.len() >= 4 {
+            fields.last().copied()
+        } else {
+            None
+        }
+    }
+
+    /// Runs can be referred to by name, experiment name or flowcell ID
+    pub fn matches(&self, query: &str) -> bool {
+        self.name == query
+            || self.experiment_name.as_deref() == Some(query)
+            || self.flowcell_id() == Some(query)
+    }
+}
+
+impl AsRef<str> for Run {
+    fn as_ref(&self) -> &str {
+        self.name.as_ref()
+    }
+}
+
 deserialize_with_root!("Response": SampleResponse);
 deserialize_with_root!("Response": FileResponse);
 deserialize_with_root!("Response": CurrentUserResponse);
 deserialize_with_root!("Response": ProjectResponse);
 deserialize_with_root!("Response": RunResponse);
+
+impl_paginated!(ProjectResponse, SampleResponse, FileResponse, RunResponse);
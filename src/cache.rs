@@ -0,0 +1,158 @@
+//! On-disk cache for listing responses (projects, samples, files, runs),
+//! so listing with -F and then downloading doesn't fetch everything twice.
+//!
+//! Responses are stored per account in
+//! $HOME/.cache/basespace-dl/{account_id}/{md5 of url}.json
+use log::{info, warn};
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Default time to live of a cached response, in seconds
+pub static DEFAULT_TTL: u64 = 600;
+
+/// The [cache] table of the config file
+#[derive(Deserialize, Debug)]
+pub struct CacheSettings {
+    /// How long a cached response is used for, in seconds. 0 disables the cache.
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+    /// Defaults to $HOME/.cache/basespace-dl
+    pub dir: Option<PathBuf>,
+}
+
+fn default_ttl() -> u64 {
+    DEFAULT_TTL
+}
+
+impl Default for CacheSettings {
+    fn default() -> CacheSettings {
+        CacheSettings {
+            ttl: DEFAULT_TTL,
+            dir: None,
+        }
+    }
+}
+
+pub struct Cache {
+    pub dir: PathBuf,
+    pub ttl: Duration,
+    /// Skip cached responses, but still store the fresh ones
+    pub refresh: bool,
+}
+
+impl Cache {
+    pub fn new(settings: &CacheSettings) -> Cache {
+        let dir = match &settings.dir {
+            Some(dir) => dir.to_owned(),
+            None => Cache::default_dir(),
+        };
+        Cache {
+            dir,
+            ttl: Duration::from_secs(settings.ttl),
+            refresh: false,
+        }
+    }
+
+    pub fn default_dir() -> PathBuf {
+        dirs::cache_dir()
+            .expect("Could not locate cache directory.")
+            .join("basespace-dl")
+    }
+
+    fn path(&self, account_id: &str, url: &str) -> PathBuf {
+        // Account IDs are numeric, but keep anything else out of the path
+        let account_dir: String = account_id
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect();
+        self.dir
+            .join(account_dir)
+            .join(format!("{:x}.json", md5::compute(url)))
+    }
+
+    /// The cached response for this url, if there is one that hasn't expired
+    pub fn get(&self, account_id: &str, url: &str) -> Option<String> {
+        if self.refresh || self.ttl.as_secs() == 0 {
+            return None;
+        }
+
+        let path = self.path(account_id, url);
+        let age = fs::metadata(&path).ok()?.modified().ok()?.elapsed().ok()?;
+        if age > self.ttl {
+            return None;
+        }
+
+        info!("Using cached response for {}", url);
+        fs::read_to_string(&path).ok()
+    }
+
+    /// The cached response for this url, however old it is.
+    /// Used in offline mode, where an old listing beats none.
+    pub fn get_stale(&self, account_id: &str, url: &str) -> Option<String> {
+        let body = fs::read_to_string(self.path(account_id, url)).ok()?;
+        info!("Using cached response for {}", url);
+        Some(body)
+    }
+
+    /// Store a response. Failing to write the cache is not fatal.
+    pub fn put(&self, account_id: &str, url: &str, body: &str) {
+        if self.ttl.as_secs() == 0 {
+            return;
+        }
+
+        let path = self.path(account_id, url);
+        let tmp = path.with_extension("json.tmp");
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&tmp, body))
+            .and_then(|_| fs::rename(&tmp, &path));
+
+        if let Err(e) = result {
+            warn!("Could not write cache file {}. {}", path.display(), e);
+        }
+    }
+
+    /// Remove every cached response. Only the files the cache wrote are
+    /// deleted, since the directory can be configured to be anywhere.
+    pub fn clear(&self) -> Result<(), failure::Error> {
+        let accounts = match fs::read_dir(&self.dir) {
+            Ok(accounts) => accounts,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for account in accounts {
+            let account = account?;
+            let is_account = account
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.chars().all(|c| c.is_ascii_alphanumeric()));
+            if !is_account || !account.file_type()?.is_dir() {
+                continue;
+            }
+
+            let mut removed = false;
+            for entry in fs::read_dir(account.path())? {
+                let path = entry?.path();
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                let digest = name
+                    .strip_suffix(".json")
+                    .or_else(|| name.strip_suffix(".json.tmp"));
+                if digest.is_some_and(|digest| {
+                    digest.len() == 32 && digest.chars().all(|c| c.is_ascii_hexdigit())
+                }) {
+                    fs::remove_file(&path)?;
+                    removed = true;
+                }
+            }
+            // Fails if anything else is in there, which isn't ours to remove
+            if removed {
+                fs::remove_dir(account.path()).ok();
+            }
+        }
+        Ok(())
+    }
+}
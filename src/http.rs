@@ -0,0 +1,321 @@
+//! Building the HTTP client from the [http] settings, and retrying
+//! requests that fail for reasons that are likely to go away, like a
+//! 502 from the gateway, a 429 or a connection reset.
+use failure::{format_err, ResultExt};
+use log::warn;
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Certificate, Proxy, StatusCode};
+use serde::Deserialize;
+use std::fs;
+use std::future::Future;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Default number of retries after the first attempt
+pub static DEFAULT_RETRIES: u32 = 4;
+/// Default seconds to wait for a connection
+pub static DEFAULT_CONNECT_TIMEOUT: u64 = 30;
+/// Default seconds to wait for the next bit of a response
+pub static DEFAULT_READ_TIMEOUT: u64 = 60;
+pub static DEFAULT_USER_AGENT: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// The [http] table of the config file
+#[derive(Deserialize, Debug, Clone)]
+pub struct HttpSettings {
+    /// How many times a failed request is retried. 0 disables retrying.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    /// Seconds to wait for a connection to the server
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// Seconds to wait for data before giving up on a response.
+    /// Applies to every read, so large downloads are not cut short.
+    #[serde(default = "default_read_timeout")]
+    pub read_timeout: u64,
+    /// Proxy for all requests. Without it, the usual
+    /// HTTP_PROXY / HTTPS_PROXY environment variables are used.
+    pub proxy: Option<String>,
+    /// PEM files of extra root certificates to trust, e.g.
+    /// the CA of a TLS inspecting firewall
+    #[serde(default)]
+    pub ca_certs: Vec<PathBuf>,
+    #[serde(default = "default_user_agent")]
+    pub user_agent: String,
+}
+
+fn default_retries() -> u32 {
+    DEFAULT_RETRIES
+}
+
+fn default_connect_timeout() -> u64 {
+    DEFAULT_CONNECT_TIMEOUT
+}
+
+fn default_read_timeout() -> u64 {
+    DEFAULT_READ_TIMEOUT
+}
+
+fn default_user_agent() -> String {
+    DEFAULT_USER_AGENT.to_owned()
+}
+
+impl Default for HttpSettings {
+    fn default() -> HttpSettings {
+        HttpSettings {
+            retries: DEFAULT_RETRIES,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            proxy: None,
+            ca_certs: vec![],
+            user_agent: default_user_agent(),
+        }
+    }
+}
+
+impl HttpSettings {
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout)
+    }
+
+    fn proxy(&self) -> Result<Option<Proxy>, failure::Error> {
+        match &self.proxy {
+            Some(url) => {
+                Ok(Some(Proxy::all(url).with_context(|e| {
+                    format!("Invalid proxy {}. {}", url, e)
+                })?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Every certificate in the ca_certs files. A file can hold a whole bundle.
+    fn certificates(&self) -> Result<Vec<Certificate>, failure::Error> {
+        let mut certificates = vec![];
+        for path in &self.ca_certs {
+            let found = certificates.len();
+            let pem = fs::read_to_string(path)
+                .with_context(|e| format!("Could not read {}. {}", path.display(), e))?;
+            let end = "-----END CERTIFICATE-----";
+            for block in pem.split_inclusive(end).filter(|block| block.contains(end)) {
+                let certificate =
+                    Certificate::from_pem(block.trim().as_bytes()).with_context(|e| {
+                        format!("Invalid certificate in {}. {}", path.display(), e)
+                    })?;
+                certificates.push(certificate);
+            }
+            if certificates.len() == found {
+                return Err(format_err!("No certificates found in {}", path.display()));
+            }
+        }
+        Ok(certificates)
+    }
+
+    /// The client for all requests. It has no overall timeout, since
+    /// downloads can take hours. Downloads apply the read timeout to
+    /// each chunk, and listing requests, being small, to the whole request.
+    pub fn client(&self) -> Result<reqwest::Client, failure::Error> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(self.connect_timeout))
+            .user_agent(&self.user_agent);
+        if let Some(proxy) = self.proxy()? {
+            builder = builder.proxy(proxy);
+        }
+        for certificate in self.certificates()? {
+            builder = builder.add_root_certificate(certificate);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// A single attempt that went wrong, and whether to try again
+#[derive(Debug)]
+pub struct Failed {
+    pub error: failure::Error,
+    pub retryable: bool,
+    /// How long the server asked us to wait (Retry-After)
+    pub retry_after: Option<Duration>,
+}
+
+impl Failed {
+    /// A failure that retrying won't fix, e.g. a full disk
+    pub fn fatal(error: impl Into<failure::Error>) -> Failed {
+        Failed {
+            error: error.into(),
+            retryable: false,
+            retry_after: None,
+        }
+    }
+
+    /// A failure that might not happen next time, e.g. a dropped connection
+    pub fn transient(error: impl Into<failure::Error>) -> Failed {
+        Failed {
+            error: error.into(),
+            retryable: true,
+            retry_after: None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Failed {
+    fn from(error: reqwest::Error) -> Failed {
+        let retryable = match error.status() {
+            Some(status) => is_retryable_status(status),
+            None => {
+                error.is_timeout() || error.is_connect() || error.is_request() || error.is_body()
+            }
+        };
+        Failed {
+            error: error.into(),
+            retryable,
+            retry_after: None,
+        }
+    }
+}
+
+/// Statuses that say "try again later" rather than "this is wrong"
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::INTERNAL_SERVER_ERROR
+        || status == StatusCode::BAD_GATEWAY
+        || status == StatusCode::SERVICE_UNAVAILABLE
+        || status == StatusCode::GATEWAY_TIMEOUT
+}
+
+/// The delay a 429 or 503 asked for in its Retry-After header.
+/// Only the delay-seconds form is understood, not an HTTP date.
+fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
+    }
+    let seconds = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+/// Send a request, treating error statuses as failed attempts
+pub async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response, Failed> {
+    let resp = request.send().await?;
+    let retry_after = retry_after(resp.status(), resp.headers());
+    resp.error_for_status().map_err(|e| Failed {
+        retry_after,
+        ..Failed::from(e)
+    })
+}
+
+/// Send a request for file content, giving up if the response doesn't
+/// start within `timeout`. The body can take far longer than that, so
+/// it is timed chunk by chunk instead of with a request timeout.
+pub async fn send_timeout(
+    request: reqwest::RequestBuilder,
+    timeout: Duration,
+) -> Result<reqwest::Response, Failed> {
+    match tokio::time::timeout(timeout, send(request)).await {
+        Ok(result) => result,
+        Err(_) => Err(Failed::transient(format_err!(
+            "no response received for {} seconds",
+            timeout.as_secs()
+        ))),
+    }
+}
+
+/// The next chunk of a response body, or None once it is all in. A
+/// server that sends nothing for `timeout` counts as a dropped connection.
+pub async fn next_chunk(
+    resp: &mut reqwest::Response,
+    timeout: Duration,
+) -> Result<Option<impl Deref<Target = [u8]>>, Failed> {
+    match tokio::time::timeout(timeout, resp.chunk()).await {
+        Ok(chunk) => Ok(chunk?),
+        Err(_) => Err(Failed::transient(format_err!(
+            "no data received for {} seconds",
+            timeout.as_secs()
+        ))),
+    }
+}
+
+/// Exponential backoff with jitter
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::new(DEFAULT_RETRIES)
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+
+    /// Delay before the nth retry (starting at 1): the base delay doubled
+    /// each time up to the max, then a random amount between half and all
+    /// of that, so parallel requests don't all come back at once.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        let delay = self
+            .base_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    /// How long to wait before retrying, or None to give up
+    fn wait(&self, retry: u32, failed: &Failed, what: &str) -> Option<Duration> {
+        if !failed.retryable || retry > self.retries {
+            return None;
+        }
+        // A server asking for longer than the max still gets retried at the max
+        let delay = failed
+            .retry_after
+            .map_or_else(|| self.backoff(retry), |after| after.min(self.max_delay));
+        warn!(
+            "{} failed ({}). Retrying in {:.1}s ({}/{})",
+            what,
+            failed.error,
+            delay.as_secs_f64(),
+            retry,
+            self.retries
+        );
+        Some(delay)
+    }
+
+    /// Run an attempt until it succeeds, fails for good, or runs out of retries
+    pub async fn run<T, F, Fut>(&self, what: &str, mut attempt: F) -> Result<T, failure::Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Failed>>,
+    {
+        let mut retry = 1;
+        loop {
+            match attempt().await {
+                Ok(value) => return Ok(value),
+                Err(failed) => match self.wait(retry, &failed, what) {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => {
+                        let message = format!("{}: {}", what, failed.error);
+                        return Err(failed.error.context(message).into());
+                    }
+                },
+            }
+            retry += 1;
+        }
+    }
+}
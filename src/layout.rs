@@ -0,0 +1,273 @@
+//! Output layouts: where each file goes under the output directory,
+//! given by a template like "{project}/{sample}/{file}".
+use crate::api::{DataFile, Project, Run, Sample};
+use failure::bail;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Every placeholder a layout can use
+pub static PLACEHOLDERS: &[&str] = &[
+    "project",
+    "project_id",
+    "project_owner",
+    "sample",
+    "sample_id",
+    "experiment_name",
+    "file",
+    "file_id",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Project,
+    ProjectId,
+    ProjectOwner,
+    Sample,
+    SampleId,
+    ExperimentName,
+    File,
+    FileId,
+}
+
+impl Placeholder {
+    fn parse(name: &str) -> Option<Placeholder> {
+        let placeholder = match name {
+            "project" => Placeholder::Project,
+            "project_id" => Placeholder::ProjectId,
+            "project_owner" => Placeholder::ProjectOwner,
+            "sample" => Placeholder::Sample,
+            "sample_id" => Placeholder::SampleId,
+            "experiment_name" => Placeholder::ExperimentName,
+            "file" => Placeholder::File,
+            "file_id" => Placeholder::FileId,
+            _ => return None,
+        };
+        Some(placeholder)
+    }
+}
+
+#[derive(Debug)]
+enum Part {
+    Text(String),
+    Value(Placeholder),
+}
+
+/// The project (or run) the files were selected from. A run fills
+/// in the project placeholders with its own name, id and owner.
+pub struct Origin<'a> {
+    pub name: &'a str,
+    pub id: &'a str,
+    pub owner: &'a str,
+}
+
+impl<'a> From<&'a Project> for Origin<'a> {
+    fn from(project: &'a Project) -> Origin<'a> {
+        Origin {
+            name: &project.name,
+            id: &project.id,
+            owner: &project.user_owned_by.name,
+        }
+    }
+}
+
+impl<'a> From<&'a Run> for Origin<'a> {
+    fn from(run: &'a Run) -> Origin<'a> {
+        Origin {
+            name: &run.name,
+            id: &run.id,
+            owner: &run.user_owned_by.name,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Layout {
+    parts: Vec<Part>,
+}
+
+impl Layout {
+    pub fn parse(template: &str) -> Result<Layout, failure::Error> {
+        let mut parts = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_owned()));
+            }
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => bail!("Unclosed {{ in layout {}", template),
+            };
+            let name = &rest[start + 1..end];
+            match Placeholder::parse(name) {
+                Some(placeholder) => parts.push(Part::Value(placeholder)),
+                None => bail!(
+                    "Unknown placeholder {{{}}} in layout {}. Expected one of {{{}}}",
+                    name,
+                    template,
+                    PLACEHOLDERS.join("}, {")
+                ),
+            }
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_owned()));
+        }
+
+        // Without the name, files would overwrite each other
+        if !parts
+            .iter()
+            .any(|part| matches!(part, Part::Value(Placeholder::File)))
+        {
+            bail!("Layout {} must contain {{file}}", template);
+        }
+        Ok(Layout { parts })
+    }
+
+    /// Where to save a file, relative to the output directory.
+    ///
+    /// Values can't add directories of their own, so a slash in e.g. a
+    /// project name becomes an underscore. The file name is the exception,
+    /// since run files are named by their path within the run.
+    pub fn render(&self, origin: &Origin, file: &DataFile) -> Result<PathBuf, failure::Error> {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            let value = match part {
+                Part::Text(text) => {
+                    rendered.push_str(text);
+                    continue;
+                }
+                Part::Value(Placeholder::File) => {
+                    rendered.push_str(&file.name);
+                    continue;
+                }
+                Part::Value(Placeholder::FileId) => file.id.as_str(),
+                Part::Value(Placeholder::Project) => origin.name,
+                Part::Value(Placeholder::ProjectId) => origin.id,
+                Part::Value(Placeholder::ProjectOwner) => origin.owner,
+                Part::Value(Placeholder::Sample) => sample(file)?.name.as_str(),
+                Part::Value(Placeholder::SampleId) => sample(file)?.id.as_str(),
+                Part::Value(Placeholder::ExperimentName) => {
+                    let sample = sample(file)?;
+                    match &sample.experiment_name {
+                        Some(experiment_name) => experiment_name.as_str(),
+                        None => bail!("Sample {} has no experiment name", sample.name),
+                    }
+                }
+            };
+            rendered.push_str(&value.replace(['/', '\\'], "_"));
+        }
+
+        // Empty segments (from "a//b" or a leading slash) are
+        // dropped, so the path is relative to the output directory
+        Ok(rendered
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect())
+    }
+}
+
+fn sample(file: &DataFile) -> Result<&Sample, failure::Error> {
+    match &file.sample {
+        Some(sample) => Ok(sample),
+        None => bail!(
+            "{} is not from a sample, so the layout can't use sample placeholders",
+            file.name
+        ),
+    }
+}
+
+/// What to do when files from different samples would be saved
+/// to the same path, e.g. the same name in a sample and its rerun
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Collisions {
+    /// Refuse to download
+    Error,
+    /// Add the sample ID to the name, e.g. sample_R1_{id}.fastq.gz
+    Suffix,
+    /// Put the file in a folder named after the sample ID
+    Nest,
+}
+
+/// Groups of files (by index) that would be saved to the same path
+fn collisions(files: &[DataFile]) -> Vec<Vec<usize>> {
+    let mut by_path: HashMap<&Path, Vec<usize>> = HashMap::new();
+    for (index, file) in files.iter().enumerate() {
+        by_path.entry(file.destination()).or_default().push(index);
+    }
+    let mut groups: Vec<Vec<usize>> = by_path
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect();
+    groups.sort();
+    groups
+}
+
+/// Give every file its own path. Either all files that collide are moved
+/// apart, so which one was listed first doesn't matter, or none are.
+pub fn resolve_collisions(
+    files: &mut [DataFile],
+    policy: Collisions,
+) -> Result<(), failure::Error> {
+    if policy != Collisions::Error {
+        for index in collisions(files).into_iter().flatten() {
+            let file = &mut files[index];
+            let sample_id = match &file.sample {
+                Some(sample) => sample.id.replace(['/', '\\'], "_"),
+                None => continue,
+            };
+            let destination = file.destination();
+            let name = destination
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            file.destination = Some(if policy == Collisions::Suffix {
+                // Before the first dot, to keep extensions like .fastq.gz
+                let stem = name
+                    .char_indices()
+                    .skip(1)
+                    .find(|(_, c)| *c == '.')
+                    .map_or(name.len(), |(index, _)| index);
+                destination.with_file_name(format!(
+                    "{}_{}{}",
+                    &name[..stem],
+                    sample_id,
+                    &name[stem..]
+                ))
+            } else {
+                destination.with_file_name(&sample_id).join(&*name)
+            });
+        }
+    }
+
+    let groups = collisions(files);
+    if groups.is_empty() {
+        return Ok(());
+    }
+    let described: Vec<String> = groups
+        .iter()
+        .map(|group| {
+            let samples: Vec<String> = group
+                .iter()
+                .map(|index| match &files[*index].sample {
+                    Some(sample) => format!("{} ({})", sample.name, sample.id),
+                    None => String::from("no sample"),
+                })
+                .collect();
+            format!(
+                "{} from {}",
+                files[group[0]].destination().display(),
+                samples.join(", ")
+            )
+        })
+        .collect();
+    let hint = if policy == Collisions::Error {
+        " Use --on-collision suffix or nest to keep them apart."
+    } else {
+        ""
+    };
+    bail!(
+        "Several files would be saved to the same path: {}.{}",
+        described.join("; "),
+        hint
+    )
+}
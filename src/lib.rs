@@ -5,65 +5,397 @@ extern crate serde_struct_wrapper;
 extern crate text_io;
 
 pub mod api;
+pub mod cache;
+pub mod http;
+pub mod layout;
+pub mod progress;
+pub mod rate;
 pub mod util;
 pub mod workspace;
 
 use api::*;
+use cache::Cache;
 use console::style;
-use crossbeam_channel::unbounded;
-use failure::bail;
+use failure::{bail, format_err, ResultExt};
+use fs2::FileExt;
 use futures::prelude::*;
-use futures::stream::futures_unordered::FuturesUnordered;
-use indicatif::ProgressBar;
+use futures::stream::{self, futures_unordered::FuturesUnordered};
+use http::{Failed, HttpSettings, RetryPolicy};
 use log::{info, warn};
+use progress::{FileProgress, Progress};
+use rate::RateLimiter;
 use rayon::prelude::*;
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
 use std::collections::HashMap;
-use std::fs;
-use std::fs::File;
-use std::io::{BufWriter, Read, Write};
-use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time::Instant;
-
-pub static RESPONSE_LIMIT: &str = "1024";
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+/// Page size for list requests (the maximum basespace allows)
+pub static RESPONSE_LIMIT: usize = 1024;
+/// Default number of files downloaded at the same time
+pub static DEFAULT_JOBS: usize = 8;
+/// Size of the write buffer of each download
+pub static DOWNLOAD_BUFFER_SIZE: usize = 1024 * 1024;
+/// Files at least this big are fetched as several byte ranges at once
+pub static SEGMENTED_MIN_SIZE: u64 = 256 * 1024 * 1024;
+/// Size of each byte range of a segmented download
+pub static SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+/// Default number of ranges of one file fetched at the same time
+pub static DEFAULT_SEGMENTS: usize = 4;
 pub static BASESPACE_URL: &str = "https://api.basespace.illumina.com/v1pre3";
 
+/// What to do with a local file that is in the way of a download
+/// and differs from the remote file. Files that match are skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Existing {
+    /// Keep the local file and don't download
+    Skip,
+    /// Replace the local file
+    Overwrite,
+    /// Move the local file to e.g. sample.bam.1, then download
+    Rename,
+    /// Don't download, and report an error
+    Fail,
+}
+
+/// Everything needed to talk to a single basespace account
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub token: String,
+    /// API root for this account, e.g. an enterprise subdomain
+    /// or a regional instance. Defaults to BASESPACE_URL.
+    pub url: String,
+    /// Cap on the bandwidth of this account's downloads
+    pub rate_limit: Option<Arc<RateLimiter>>,
+}
+
+impl Account {
+    pub fn new(token: &str, url: Option<&str>) -> Account {
+        Account {
+            token: token.to_owned(),
+            url: url
+                .unwrap_or(BASESPACE_URL)
+                .trim_end_matches('/')
+                .to_owned(),
+            rate_limit: None,
+        }
+    }
+
+    /// Look up the user the token belongs to. Fails if
+    /// the token is not valid for this url.
+    pub async fn current_user(
+        &self,
+        client: &reqwest::Client,
+        timeout: Duration,
+    ) -> Result<CurrentUserResponse, failure::Error> {
+        let user = client
+            .get(format!("{}/users/current", self.url))
+            .header("x-access-token", &self.token)
+            .timeout(timeout)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<CurrentUserResponse>()
+            .await?;
+        Ok(user)
+    }
+}
+
+/// A listing request that could not be completed
+#[derive(Debug)]
+pub struct FetchFailure {
+    pub account_id: String,
+    pub sample_id: Option<String>,
+    pub status: Option<reqwest::StatusCode>,
+    pub error: failure::Error,
+}
+
+impl FetchFailure {
+    pub fn new(account_id: &str, sample_id: Option<&str>, error: failure::Error) -> FetchFailure {
+        let status = find_cause::<reqwest::Error>(&error).and_then(|e| e.status());
+        FetchFailure {
+            account_id: account_id.to_owned(),
+            sample_id: sample_id.map(|id| id.to_owned()),
+            status,
+            error,
+        }
+    }
+}
+
+impl fmt::Display for FetchFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "account {}", self.account_id)?;
+        if let Some(sample_id) = &self.sample_id {
+            write!(f, ", sample {}", sample_id)?;
+        }
+        match self.status {
+            Some(status) => write!(f, ": HTTP {}", status),
+            None => write!(f, ": {}", self.error),
+        }
+    }
+}
+
+/// Items from a listing spread over several requests, along with
+/// the requests that failed. Callers decide whether a partial
+/// result is good enough.
+#[derive(Debug)]
+pub struct Fetched<T> {
+    pub items: Vec<T>,
+    pub failures: Vec<FetchFailure>,
+}
+
+impl<T> Fetched<T> {
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
 pub struct MultiApi {
-    pub accounts: HashMap<String, String>,
+    pub accounts: HashMap<String, Account>,
+    pub cache: Option<Cache>,
+    /// Only answer listings from the cache, never from the network
+    pub offline: bool,
+    pub retry: RetryPolicy,
+    pub http: HttpSettings,
+    /// Shared by every request, so connections are reused
+    pub client: reqwest::Client,
+    /// How many files to download at the same time
+    pub jobs: usize,
+    /// How many byte ranges of a large file to fetch at the same time.
+    /// 1 downloads every file as a single stream.
+    pub segments: usize,
+    /// Cap on the bandwidth of all downloads together
+    pub rate_limit: Option<RateLimiter>,
+    /// Download even if the output directory seems to lack the space
+    pub force: bool,
+    pub on_existing: Existing,
+}
+
+/// Refuse to start downloads that won't fit, rather than filling the
+/// disk halfway through. Bytes left by an earlier run are already on disk.
+fn check_free_space(files: &[&DataFile], output_dir: &Path) -> Result<(), failure::Error> {
+    let needed: u64 = files
+        .iter()
+        .map(|file| {
+            let size = file.size as u64;
+            size - util::bytes_present(&output_dir.join(file.destination())).min(size)
+        })
+        .sum();
+    let available = fs2::available_space(output_dir).with_context(|e| {
+        format!(
+            "Could not get the free space in {}. {}",
+            output_dir.display(),
+            e
+        )
+    })?;
+    if needed > available {
+        bail!(
+            "Not enough space in {}: {} needed, {} free. Free up some space or use --force.",
+            output_dir.display(),
+            util::convert_bytes(needed as f64),
+            util::convert_bytes(available as f64)
+        );
+    }
+    Ok(())
 }
 
 impl MultiApi {
-    pub fn new(accounts: HashMap<String, String>) -> MultiApi {
-        MultiApi { accounts }
+    pub fn new(accounts: HashMap<String, Account>) -> MultiApi {
+        MultiApi {
+            accounts,
+            cache: None,
+            offline: false,
+            retry: RetryPolicy::default(),
+            http: HttpSettings::default(),
+            client: reqwest::Client::new(),
+            jobs: DEFAULT_JOBS,
+            segments: DEFAULT_SEGMENTS,
+            rate_limit: None,
+            force: false,
+            on_existing: Existing::Fail,
+        }
     }
-    
-    pub async fn get_projects(&self) -> Result<Vec<Project>, failure::Error> {
-        let client = reqwest::Client::new();
+
+    fn account(&self, user_id: &str) -> &Account {
+        self.accounts
+            .get(user_id)
+            .expect("Could not get account from accounts")
+    }
+
+    /// Fetch every page of a list endpoint, following the
+    /// Offset / Limit / TotalCount envelope until all items are in.
+    /// Responses without a TotalCount are paged until a short page.
+    ///
+    /// Pages are read through the response cache, if there is one.
+    /// In offline mode they come only from the cache, however old.
+    async fn get_all_pages<T>(
+        &self,
+        account_id: &str,
+        url: String,
+    ) -> Result<Vec<T>, failure::Error>
+    where
+        T: Paginated + DeserializeOwned,
+    {
+        let mut pages = vec![];
+        let mut offset = 0;
+
+        loop {
+            let page_url = format!("{}?offset={}&limit={}", url, offset, RESPONSE_LIMIT);
+            let body = if self.offline {
+                match self
+                    .cache
+                    .as_ref()
+                    .and_then(|cache| cache.get_stale(account_id, &page_url))
+                {
+                    Some(body) => body,
+                    None => bail!(
+                        "{} is not in the cache. Run once without --offline to fetch it.",
+                        page_url
+                    ),
+                }
+            } else {
+                self.fetch_page(account_id, &page_url).await?
+            };
+            let page: T = serde_json::from_str(&body)?;
+
+            offset += page.len();
+            let done = page.is_last(offset, RESPONSE_LIMIT);
+            pages.push(page);
+
+            if done {
+                break;
+            }
+            info!("Fetched {} items from {}, requesting more", offset, url);
+        }
+
+        Ok(pages)
+    }
+
+    /// Fetch one page of a listing, from the cache if it's still fresh
+    async fn fetch_page(&self, account_id: &str, page_url: &str) -> Result<String, failure::Error> {
+        if let Some(body) = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.get(account_id, page_url))
+        {
+            return Ok(body);
+        }
+
+        let client = &self.client;
+        let token = &self.account(account_id).token;
+        let timeout = self.http.read_timeout();
+        let body = self
+            .retry
+            .run(page_url, || async move {
+                let request = client
+                    .get(page_url)
+                    .header("x-access-token", token)
+                    .timeout(timeout);
+                let resp = http::send(request).await?;
+                Ok(resp.text().await?)
+            })
+            .await?;
+        if let Some(cache) = &self.cache {
+            cache.put(account_id, page_url, &body);
+        }
+        Ok(body)
+    }
+
+    /// Fetch a listing from every account, e.g. all projects.
+    /// An account that fails (e.g. an expired token) is reported in the failures.
+    async fn get_from_accounts<T, R>(
+        &self,
+        endpoint: &str,
+        as_user: impl Fn(R, &str) -> Vec<T>,
+    ) -> Result<Fetched<T>, failure::Error>
+    where
+        R: Paginated + DeserializeOwned,
+    {
         let mut futures = FuturesUnordered::new();
 
-        for (account_id, token) in &self.accounts {
-            info!("Fetching projects for account {}", account_id);
-            let account_id = account_id.to_owned();
-            let resp = client
-                .get(&format!(
-                    "{}/users/current/projects?limit={}",
-                    BASESPACE_URL, RESPONSE_LIMIT
-                ))
-                .header("x-access-token", token)
-                .send()
-                .and_then(|x| x.json::<ProjectResponse>())
-                .map(|x| (account_id, x));
+        for (account_id, account) in &self.accounts {
+            info!("Fetching {} for account {}", endpoint, account_id);
+            let url = format!("{}/users/current/{}", account.url, endpoint);
+            let resp = self
+                .get_all_pages::<R>(account_id, url)
+                .map(move |x| (account_id, x));
             futures.push(resp);
         }
 
-        let mut projects = vec![];
-        while let Some((account_id, response)) = futures.next().await {
-            if let Ok(response) = response {
-                projects.extend(response.projects_as_user(&account_id));
+        let mut items = vec![];
+        let mut failures = vec![];
+        while let Some((account_id, pages)) = futures.next().await {
+            match pages {
+                Ok(pages) => {
+                    for page in pages {
+                        items.extend(as_user(page, account_id));
+                    }
+                }
+                Err(e) => failures.push(FetchFailure::new(account_id, None, e)),
             }
         }
 
-        Ok(projects)
+        Ok(Fetched { items, failures })
+    }
+
+    /// Fetch projects from every account
+    pub async fn get_projects(&self) -> Result<Fetched<Project>, failure::Error> {
+        self.get_from_accounts("projects", ProjectResponse::projects_as_user)
+            .await
+    }
+
+    /// Fetch sequencing runs from every account
+    pub async fn get_runs(&self) -> Result<Fetched<Run>, failure::Error> {
+        self.get_from_accounts("runs", RunResponse::runs_as_user)
+            .await
+    }
+
+    /// Fetch the output files of a run (InterOp, logs, etc.). These
+    /// are named by their path within the run folder, since the same
+    /// file name shows up in many subfolders.
+    pub async fn get_run_files(&self, run: &Run) -> Result<Vec<DataFile>, failure::Error> {
+        let account = self.account(&run.user_fetched_by_id);
+
+        let url = format!("{}/runs/{}/files", account.url, run.id);
+
+        let files = self
+            .get_all_pages::<FileResponse>(&run.user_fetched_by_id, url)
+            .await?
+            .into_iter()
+            .flat_map(|page| page.items)
+            .map(|mut file| {
+                if let Some(path) = file.path.take() {
+                    file.name = path;
+                }
+                file
+            })
+            .collect();
+
+        Ok(files)
+    }
+
+    /// Fetch the demultiplexed samples of a run
+    pub async fn get_run_samples(&self, run: &Run) -> Result<Vec<Sample>, failure::Error> {
+        let account = self.account(&run.user_fetched_by_id);
+
+        let url = format!("{}/runs/{}/samples", account.url, run.id);
+
+        let samples = self
+            .get_all_pages::<SampleResponse>(&run.user_fetched_by_id, url)
+            .await?
+            .into_iter()
+            .flat_map(|page| page.items)
+            .collect();
+
+        Ok(samples)
     }
 
     pub async fn get_undetermined_sample(
@@ -107,181 +439,824 @@ impl MultiApi {
     }
 
     pub async fn get_samples(&self, project: &Project) -> Result<Vec<Sample>, failure::Error> {
-        let token = self
-            .accounts
-            .get(&project.user_fetched_by_id)
-            .expect("Could not get token from accounts");
-
-        let client = reqwest::Client::new();
-
-        let samples = client
-            .get(&format!(
-                "{}/projects/{}/samples?limit={}",
-                BASESPACE_URL, project.id, RESPONSE_LIMIT
-            ))
-            .header("x-access-token", token)
-            .send()
-            .await?
-            .json::<SampleResponse>()
+        let account = self.account(&project.user_fetched_by_id);
+
+        let url = format!("{}/projects/{}/samples", account.url, project.id);
+
+        let samples = self
+            .get_all_pages::<SampleResponse>(&project.user_fetched_by_id, url)
             .await?
-            .items;
+            .into_iter()
+            .flat_map(|page| page.items)
+            .collect();
 
         Ok(samples)
     }
 
+    /// Fetch the files of every sample, using the account the samples
+    /// were found through. A sample that fails is reported in the failures.
     pub async fn get_files(
         &self,
-        project: &Project,
+        account_id: &str,
         samples: &[Sample],
-    ) -> Result<Vec<DataFile>, failure::Error> {
-        let token = self
-            .accounts
-            .get(&project.user_fetched_by_id)
-            .expect("Could not get token from accounts");
+    ) -> Result<Fetched<DataFile>, failure::Error> {
+        let account = self.account(account_id);
 
-        let client = reqwest::Client::new();
         let mut file_futures = FuturesUnordered::new();
 
         for sample in samples {
-            let resp = client
-                .get(&format!("{}/samples/{}/files", BASESPACE_URL, sample.id))
-                .header("x-access-token", token)
-                .send();
+            let url = format!("{}/samples/{}/files", account.url, sample.id);
+            let resp = self
+                .get_all_pages::<FileResponse>(account_id, url)
+                .map(move |x| (sample, x));
             file_futures.push(resp);
         }
 
         let mut files = vec![];
-        while let Some(response) = file_futures.next().await {
-            if let Ok(response) = response {
-                let response = response.json::<FileResponse>().await?;
-                files.extend(response.items);
+        let mut failures = vec![];
+        while let Some((sample, pages)) = file_futures.next().await {
+            match pages {
+                Ok(pages) => {
+                    for page in pages {
+                        files.extend(page.items.into_iter().map(|mut file| {
+                            file.sample = Some(sample.clone());
+                            file
+                        }));
+                    }
+                }
+                Err(e) => failures.push(FetchFailure::new(account_id, Some(&sample.id), e)),
             }
         }
 
-        Ok(files)
+        Ok(Fetched {
+            items: files,
+            failures,
+        })
     }
 
-    pub fn download_files(
+    pub async fn download_files(
         &self,
         files: &[DataFile],
-        project: &Project,
+        account_id: &str,
         output_dir: impl AsRef<Path>,
     ) -> Result<(), failure::Error> {
+        if self.offline {
+            bail!("Cannot download in offline mode. Use -F to list the files instead.");
+        }
         if files.is_empty() {
             bail!("Selected 0 files to download");
         }
 
-        let token = self
-            .accounts
-            .get(&project.user_fetched_by_id)
-            .expect("Could not get token from accounts");
+        let account = self.account(account_id);
 
         let output_dir = output_dir.as_ref();
+
+        // Names come from the server, so a hostile one could point
+        // anywhere. Those files fail, and the rest go ahead.
+        let mut rejected = vec![];
+        let files: Vec<&DataFile> = files
+            .iter()
+            .filter(|file| match util::check_destination(file.destination()) {
+                Ok(()) => true,
+                Err(e) => {
+                    rejected.push((*file, Err(e)));
+                    false
+                }
+            })
+            .collect();
+
+        // Files that are already complete in the output directory don't
+        // need to be fetched again, so rerunning a command is cheap.
+        // Hashing is slow on big files, so keep it off the runtime.
+        let present: Vec<bool> = {
+            let files: Vec<(PathBuf, DataFile)> = files
+                .iter()
+                .map(|file| (output_dir.join(file.destination()), (*file).clone()))
+                .collect();
+            tokio::task::spawn_blocking(move || {
+                files
+                    .into_par_iter()
+                    .map(|(path, file)| util::matches_remote(&path, &file).unwrap_or(false))
+                    .collect()
+            })
+            .await?
+        };
+        let (skipped, files): (Vec<_>, Vec<_>) = files
+            .into_iter()
+            .zip(present)
+            .partition(|(_, present)| *present);
+        let skipped: Vec<&DataFile> = skipped.into_iter().map(|(file, _)| file).collect();
+        let files: Vec<&DataFile> = files.into_iter().map(|(file, _)| file).collect();
+
+        // Anything still in the way differs from the remote file,
+        // and is never replaced without saying so
+        let mut kept: Vec<&DataFile> = vec![];
+        let files: Vec<&DataFile> = files
+            .into_iter()
+            .filter(|file| {
+                let output = output_dir.join(file.destination());
+                if std::fs::symlink_metadata(&output).is_err() {
+                    return true;
+                }
+                match self.on_existing {
+                    Existing::Skip => {
+                        warn!(
+                            "Keeping {}, which differs from the remote file",
+                            output.display()
+                        );
+                        kept.push(*file);
+                        false
+                    }
+                    Existing::Overwrite => {
+                        warn!(
+                            "Overwriting {}, which differs from the remote file",
+                            output.display()
+                        );
+                        true
+                    }
+                    // Moved just before its download starts, so
+                    // nothing is renamed if the run stops before then
+                    Existing::Rename => true,
+                    Existing::Fail => {
+                        rejected.push((
+                            *file,
+                            Err(format_err!(
+                                "{} already exists and differs from the remote file. \
+                                 Use --on-existing to skip, overwrite or rename it.",
+                                output.display()
+                            )),
+                        ));
+                        false
+                    }
+                }
+            })
+            .collect();
+
+        let mut skipped_note = String::new();
+        if !skipped.is_empty() {
+            info!(
+                "Skipping {} files already in {}",
+                skipped.len(),
+                output_dir.display()
+            );
+            skipped_note += &format!(", skipped {} already present", skipped.len());
+        }
+        if !kept.is_empty() {
+            skipped_note += &format!(", kept {} that differ", kept.len());
+        }
+
+        if files.is_empty() && rejected.is_empty() {
+            if kept.is_empty() {
+                eprintln!(
+                    "{} All {} files already present in {}",
+                    style("success:").bold().green(),
+                    skipped.len(),
+                    output_dir.display()
+                );
+            } else {
+                eprintln!(
+                    "{} Nothing downloaded to {}{}",
+                    style("warning:").bold().yellow(),
+                    output_dir.display(),
+                    skipped_note
+                );
+            }
+            return Ok(());
+        }
+
         let num_files = files.len();
         let total_size: i64 = files.iter().map(|file| file.size).sum();
+        if !self.force {
+            check_free_space(&files, output_dir)?;
+        }
         let index = AtomicUsize::new(1);
         let time_before = Instant::now();
-        let pb = ProgressBar::new(num_files as u64);
-
-        // Catch badly download files, so we can inform the user
-        // afterwards that they need to download them again
-        let (tx, rx) = unbounded::<DataFile>();
-        let catcher = std::thread::spawn(move || {
-            let mut files = vec![];
-            while let Ok(file) = rx.recv() {
-                files.push(file);
-            }
-            files
-        });
+        let progress = Progress::new(num_files, total_size as u64);
 
-        let errors: Vec<failure::Error> = files
-            .par_iter()
+        let mut results: Vec<(&DataFile, Result<(), failure::Error>)> = stream::iter(files)
             .map(|file| {
                 let index = index.fetch_add(1, Ordering::SeqCst);
-                pb.println(&format!(
+                progress.println(&format!(
                     "{:<9} {:>4}  {}",
                     style(&format!("[{}/{}]", index, num_files)).bold().dim(),
                     util::convert_bytes(file.size as f64),
-                    &file.name,
+                    file.destination().display(),
                 ));
+                let output = output_dir.join(file.destination());
+                let file_progress = progress.start_file(&file.name, file.size as u64);
+                async move {
+                    let result = self
+                        .download_file(account, file, &output, &file_progress)
+                        .await;
+                    (file, result)
+                }
+            })
+            .buffer_unordered(self.jobs.max(1))
+            .collect()
+            .await;
 
-                let client = reqwest::blocking::Client::new();
-                let mut resp = client
-                    .get(&format!("{}/files/{}/content", BASESPACE_URL, file.id))
-                    .header("x-access-token", token)
-                    .send()?;
-                let output = output_dir.join(&file.name);
+        progress.finish();
+        let elapsed = time_before.elapsed().as_millis();
+        results.extend(rejected);
 
-                // Need separate scope since we need to close
-                // the file before calculating the etag.
-                {
-                    let mut writer = BufWriter::new(File::create(&output)?);
-                    loop {
-                        let mut buffer = vec![0; 1024];
-                        let bcount = resp.read(&mut buffer[..]).unwrap();
-                        buffer.truncate(bcount);
-                        if !buffer.is_empty() {
-                            writer.write_all(&buffer).unwrap();
-                        } else {
-                            pb.inc(1);
-                            break;
-                        }
-                    }
-                }
+        // Remember the files that failed, so we can tell the
+        // user how to download just those again afterwards
+        let num_results = results.len();
+        let (bad_files, errors): (Vec<&DataFile>, Vec<failure::Error>) = results
+            .into_iter()
+            .filter_map(|(file, result)| result.err().map(|e| (file, e)))
+            .unzip();
+
+        // Very quick runs can take 0 ms
+        let speed = ((total_size as f64) / (elapsed.max(1) as f64)) * 1000.0;
 
-                if fs::metadata(&output)?.len() != file.size as u64 {
-                    tx.send(file.clone()).unwrap();
-                    bail!("{} did not match expected file size.", file.name);
+        if errors.is_empty() {
+            eprintln!(
+                "{} Downloaded {} files at {}/s{}",
+                style("success:").bold().green(),
+                num_files,
+                util::convert_bytes(speed),
+                skipped_note
+            );
+        } else {
+            eprintln!(
+                "{} Download {} files at {}/s{}, but there were {} errors.",
+                style("warning:").bold().yellow(),
+                num_files,
+                util::convert_bytes(speed),
+                skipped_note,
+                errors.len()
+            );
+            for error in &errors {
+                eprintln!("{}", error);
+            }
+            if !bad_files.is_empty() {
+                let log_file = std::env::temp_dir().join("bdl_last_failed_download");
+                let mut writer = std::fs::File::create(&log_file)
+                    .expect("Could not create log file for badly formatted files");
+                for file in bad_files {
+                    writeln!(&mut writer, "{}", file.name).unwrap();
                 }
+                eprintln!(
+                    "{} Files stored in {}. You can retry downloading \
+                     just these files using the -f argument",
+                    style("tip:").bold().cyan(),
+                    log_file.to_str().unwrap()
+                );
+            }
+            bail!("{} of {} files failed", errors.len(), num_results);
+        }
 
-                Ok(())
-            })
-            .filter_map(|res| res.err())
-            .collect();
+        Ok(())
+    }
 
-        drop(tx);
-        pb.finish_and_clear();
-        let elapsed = time_before.elapsed().as_millis();
-        let bad_files = catcher.join().unwrap();
+    /// With --on-existing rename, move a local file that
+    /// differs from the remote one out of the way
+    fn make_way(&self, output: &Path) -> Result<(), failure::Error> {
+        if self.on_existing == Existing::Rename && std::fs::symlink_metadata(output).is_ok() {
+            let moved = util::move_aside(output)?;
+            warn!(
+                "Moved {}, which differs from the remote file, to {}",
+                output.display(),
+                moved.display()
+            );
+        }
+        Ok(())
+    }
 
-        if elapsed > 0 {
-            let speed = ((total_size as f64) / (elapsed as f64)) * 1000.0;
+    /// Download a single file to its .part file, check it, and
+    /// move it into place
+    async fn download_file(
+        &self,
+        account: &Account,
+        file: &DataFile,
+        output: &Path,
+        progress: &FileProgress<'_>,
+    ) -> Result<(), failure::Error> {
+        self.make_way(output)?;
+        let expected_size = file.size as u64;
+        if let Some(parent) = output.parent() {
+            fs::create_dir_all(parent).await?;
+        }
 
-            if errors.is_empty() {
-                eprintln!(
-                    "{} Downloaded {} files at {}/s",
-                    style("success:").bold().green(),
-                    num_files,
-                    util::convert_bytes(speed)
+        // A .part file left by a single stream download is resumed that
+        // way, even if the file is large, and a segments file is resumed
+        // in segments, even with --segments 1.
+        let single_part = util::part_path(output);
+        let (part, done) = util::segments_paths(output);
+        let segmented = fs::metadata(&single_part).await.is_err()
+            && (fs::metadata(&part).await.is_ok()
+                || (self.segments > 1 && expected_size >= SEGMENTED_MIN_SIZE));
+        let (part, done, downloaded) = if segmented {
+            match self
+                .fetch_segments(account, file, &part, &done, progress)
+                .await
+            {
+                Ok(downloaded) => (part, Some(done), downloaded),
+                // Without the space reserved for the ranges, which
+                // would otherwise count as already downloaded
+                Err(e) if find_cause::<RangesUnsupported>(&e).is_some() => {
+                    warn!(
+                        "{}, so downloading {} as one stream",
+                        RangesUnsupported, file.name
+                    );
+                    let counted: u64 = fs::read_to_string(&done)
+                        .await
+                        .map_or(0, |text| util::segments_received(&text).values().sum());
+                    fs::remove_file(&part).await?;
+                    fs::remove_file(&done).await?;
+                    progress.restart(counted);
+                    let downloaded = self
+                        .fetch_single(account, file, &single_part, progress)
+                        .await?;
+                    (single_part, None, downloaded)
+                }
+                Err(e) => return Err(e),
+            }
+        } else {
+            let downloaded = self
+                .fetch_single(account, file, &single_part, progress)
+                .await?;
+            (single_part, None, downloaded)
+        };
+
+        // Keep the part file around so the next run can resume it
+        if downloaded != expected_size {
+            bail!("{} did not match expected file size.", file.name);
+        }
+
+        // The part file is synced before it is checked, so what was
+        // verified is what is on disk when it gets the final name.
+        // Hashing is slow on big files, so keep it off the runtime.
+        progress.set_status("verifying");
+        let synced = {
+            let part = part.clone();
+            tokio::task::spawn_blocking(move || {
+                let part = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(&part)?;
+                part.sync_all().map(|_| part)
+            })
+            .await??
+        };
+        let e_tag = file.e_tag.clone();
+        let verified = tokio::task::spawn_blocking(move || {
+            util::verify_s3_etag(synced, &e_tag, expected_size)
+        })
+        .await?;
+        match verified {
+            Ok(true) => {}
+            // Multipart etags can only be checked against the usual part
+            // sizes, so the data may well be fine. Keep it to look at, but
+            // out of the way, so the next run downloads the file again.
+            Ok(false) => {
+                let kept = util::mismatch_path(output);
+                fs::rename(&part, &kept).await?;
+                if let Some(done) = &done {
+                    fs::remove_file(done).await?;
+                }
+                bail!(
+                    "{} did not match expected etag {}. The download was moved to {}.",
+                    file.name,
+                    file.e_tag,
+                    kept.display()
                 );
+            }
+            Err(e) => warn!("Could not verify etag of {}. {}", file.name, e),
+        }
+        // Only complete files ever have the final name
+        fs::rename(&part, output)
+            .await
+            .with_context(|e| format!("Could not move {} into place. {}", file.name, e))?;
+        if let Some(parent) = output.parent() {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".").to_owned()
             } else {
-                eprintln!(
-                    "{} Download {} files at {}/s, but there were {} errors.",
-                    style("warning:").bold().yellow(),
-                    num_files,
-                    util::convert_bytes(speed),
-                    errors.len()
-                );
-                for error in errors {
-                    eprintln!("{}", error);
+                parent.to_owned()
+            };
+            tokio::task::spawn_blocking(move || util::sync_dir(&parent)).await??;
+        }
+        if let Some(done) = &done {
+            fs::remove_file(done).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the content of a file to stdout, to pipe it into another
+    /// tool without keeping a copy. Nothing is written to disk, so
+    /// the size is checked, but the etag can't be.
+    pub async fn cat_file(&self, file: &DataFile, account_id: &str) -> Result<(), failure::Error> {
+        if self.offline {
+            bail!("Cannot download in offline mode. Use -F to list the files instead.");
+        }
+
+        let account = self.account(account_id);
+        let size = file.size as u64;
+        let progress = Progress::new(1, size);
+        let file_progress = progress.start_file(&file.name, size);
+        let stdout = tokio::sync::Mutex::new(BufWriter::with_capacity(
+            DOWNLOAD_BUFFER_SIZE,
+            tokio::io::stdout(),
+        ));
+        let written = AtomicU64::new(0);
+        let result = self
+            .retry
+            .run(&file.name, || {
+                self.cat_part(account, file, &stdout, &written, &file_progress)
+            })
+            .await;
+        drop(file_progress);
+        progress.finish();
+
+        match result {
+            // Whatever reads stdout stopped early, e.g. head
+            Err(e) if is_broken_pipe(&e) => Ok(()),
+            result => result,
+        }
+    }
+
+    /// One attempt at writing a file to stdout, carrying on after
+    /// the bytes that earlier attempts have `written`
+    async fn cat_part(
+        &self,
+        account: &Account,
+        file: &DataFile,
+        stdout: &tokio::sync::Mutex<BufWriter<tokio::io::Stdout>>,
+        written: &AtomicU64,
+        progress: &FileProgress<'_>,
+    ) -> Result<(), Failed> {
+        let expected_size = file.size as u64;
+        let mut stdout = stdout.lock().await;
+        let mut offset = written.load(Ordering::SeqCst);
+
+        let mut request = self
+            .client
+            .get(format!("{}/files/{}/content", account.url, file.id))
+            .header("x-access-token", &account.token);
+        if offset > 0 {
+            info!("Resuming {} from byte {}", file.name, offset);
+            request = request.header(RANGE, format!("bytes={}-", offset));
+        }
+        let read_timeout = self.http.read_timeout();
+        let mut resp = http::send_timeout(request, read_timeout).await?;
+
+        // What was written can't be taken back, so if the server
+        // ignored the range, skip the bytes we already have
+        let mut skip = if resp.status() == StatusCode::PARTIAL_CONTENT {
+            0
+        } else {
+            offset
+        };
+        while let Some(chunk) = http::next_chunk(&mut resp, read_timeout).await? {
+            self.throttle(account, chunk.len()).await;
+            let skipped = skip.min(chunk.len() as u64);
+            skip -= skipped;
+            let chunk = &chunk[skipped as usize..];
+            if offset + chunk.len() as u64 > expected_size {
+                return Err(Failed::fatal(format_err!(
+                    "the server sent more than {} bytes",
+                    expected_size
+                )));
+            }
+            stdout.write_all(chunk).await.map_err(Failed::fatal)?;
+            offset += chunk.len() as u64;
+            written.store(offset, Ordering::SeqCst);
+            progress.inc(chunk.len() as u64);
+        }
+        stdout.flush().await.map_err(Failed::fatal)?;
+
+        if offset < expected_size {
+            return Err(Failed::transient(format_err!(
+                "connection closed after {} of {} bytes",
+                offset,
+                expected_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Wait until the bandwidth limits allow another `bytes`
+    async fn throttle(&self, account: &Account, bytes: usize) {
+        if let Some(limit) = &self.rate_limit {
+            limit.acquire(bytes).await;
+        }
+        if let Some(limit) = &account.rate_limit {
+            limit.acquire(bytes).await;
+        }
+    }
+
+    /// Download a file as one stream, resuming what
+    /// earlier runs left in its part file
+    async fn fetch_single(
+        &self,
+        account: &Account,
+        file: &DataFile,
+        part: &Path,
+        progress: &FileProgress<'_>,
+    ) -> Result<u64, failure::Error> {
+        let expected_size = file.size as u64;
+
+        // Pick up where a previous run left off. A part file
+        // larger than the remote file can't be resumed.
+        let mut downloaded = fs::metadata(part)
+            .await
+            .map_or(0, |metadata| metadata.len());
+        if downloaded > expected_size {
+            warn!("Discarding {}, it is larger than expected", part.display());
+            fs::remove_file(part).await?;
+            downloaded = 0;
+        }
+
+        progress.resume(downloaded);
+
+        if downloaded < expected_size {
+            downloaded = self
+                .retry
+                .run(&file.name, || {
+                    self.fetch_part(account, file, part, progress)
+                })
+                .await?;
+        } else if expected_size == 0 {
+            // There is nothing to fetch, but there still has to
+            // be a part file to move into place
+            File::create(part).await?;
+        }
+        Ok(downloaded)
+    }
+
+    /// Download a large file as several byte ranges at once, each written
+    /// in place into a preallocated part file. How far each range got is
+    /// logged in `done` as it is written, so an interrupted download
+    /// only fetches the rest next time. Returns how many bytes the
+    /// ranges add up to.
+    async fn fetch_segments(
+        &self,
+        account: &Account,
+        file: &DataFile,
+        part: &Path,
+        done: &Path,
+        progress: &FileProgress<'_>,
+    ) -> Result<u64, failure::Error> {
+        let expected_size = file.size as u64;
+
+        let out = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(part)?;
+        let received: HashMap<u64, u64> = match std::fs::read_to_string(done) {
+            Ok(text) if out.metadata()?.len() == expected_size => util::segments_received(&text),
+            _ => {
+                // Reserve the space up front, so a full disk fails now
+                // rather than hours in
+                out.set_len(0)?;
+                out.allocate(expected_size)?;
+                std::fs::File::create(done)?;
+                HashMap::new()
+            }
+        };
+        let out = Arc::new(out);
+        let done_file = Mutex::new(std::fs::OpenOptions::new().append(true).open(done)?);
+
+        let segments: Vec<(u64, u64)> = (0..expected_size)
+            .step_by(SEGMENT_SIZE as usize)
+            .map(|start| (start, (start + SEGMENT_SIZE).min(expected_size)))
+            .collect();
+        let resumed = |start: u64, end: u64| {
+            received
+                .get(&start)
+                .map_or(0, |&bytes| bytes.min(end - start))
+        };
+        let already: u64 = segments
+            .iter()
+            .map(|&(start, end)| resumed(start, end))
+            .sum();
+        if already > 0 {
+            info!("Resuming {} with {} bytes already done", file.name, already);
+        }
+        progress.resume(already);
+
+        let results: Vec<Result<u64, failure::Error>> = stream::iter(segments)
+            .filter(|&(start, end)| future::ready(resumed(start, end) < end - start))
+            .map(|(start, end)| {
+                let out = &out;
+                let received = Received {
+                    start,
+                    bytes: AtomicU64::new(resumed(start, end)),
+                    log: &done_file,
+                };
+                async move {
+                    let what = format!("{} bytes {}-{}", file.name, start, end - 1);
+                    let before = received.load();
+                    self.retry
+                        .run(&what, || {
+                            self.fetch_range(account, file, out, (start, end), &received, progress)
+                        })
+                        .await?;
+                    Ok(end - start - before)
                 }
-                if !bad_files.is_empty() {
-                    let log_file = std::env::temp_dir().join("bdl_last_failed_download");
-                    let mut writer = File::create(&log_file)
-                        .expect("Could not create log file for badly formatted files");
-                    for file in bad_files {
-                        writeln!(&mut writer, "{}", file.name).unwrap();
+            })
+            .buffer_unordered(self.segments.max(1))
+            .collect()
+            .await;
+
+        let mut downloaded = already;
+        for result in results {
+            downloaded += result?;
+        }
+        Ok(downloaded)
+    }
+
+    /// One attempt at fetching bytes `start..end` of a file into place in
+    /// `out`, carrying on after what earlier attempts and runs `received`
+    async fn fetch_range(
+        &self,
+        account: &Account,
+        file: &DataFile,
+        out: &Arc<std::fs::File>,
+        (start, end): (u64, u64),
+        received: &Received<'_>,
+        progress: &FileProgress<'_>,
+    ) -> Result<(), Failed> {
+        let mut offset = start + received.load();
+        let request = self
+            .client
+            .get(format!("{}/files/{}/content", account.url, file.id))
+            .header("x-access-token", &account.token)
+            .header(RANGE, format!("bytes={}-{}", offset, end - 1));
+        let read_timeout = self.http.read_timeout();
+        let mut resp = http::send_timeout(request, read_timeout).await?;
+        if resp.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(Failed::fatal(RangesUnsupported));
+        }
+
+        // Chunks are gathered into a large buffer, which is
+        // written out and reused once it fills up
+        let mut buffer = Vec::with_capacity(DOWNLOAD_BUFFER_SIZE);
+        let result = loop {
+            match http::next_chunk(&mut resp, read_timeout).await {
+                Ok(Some(chunk)) => {
+                    if offset + (buffer.len() + chunk.len()) as u64 > end {
+                        break Err(Failed::fatal(format_err!(
+                            "the server sent more than the requested range"
+                        )));
+                    }
+                    self.throttle(account, chunk.len()).await;
+                    progress.inc(chunk.len() as u64);
+                    buffer.extend_from_slice(&chunk);
+                    if buffer.len() >= DOWNLOAD_BUFFER_SIZE {
+                        buffer = write_at(out, buffer, offset).await?;
+                        offset += buffer.len() as u64;
+                        received.store(offset - start)?;
+                        buffer.clear();
                     }
-                    eprintln!(
-                        "{} Files stored in {}. You can retry downloading \
-                         just these files using the -f argument",
-                        style("tip:").bold().cyan(),
-                        log_file.to_str().unwrap()
-                    );
                 }
+                Ok(None) => break Ok(()),
+                Err(failed) => break Err(failed),
             }
+        };
+        // Whatever was received is kept for the next attempt
+        if !buffer.is_empty() {
+            buffer = write_at(out, buffer, offset).await?;
+            offset += buffer.len() as u64;
+            received.store(offset - start)?;
         }
+        result?;
 
+        if offset < end {
+            return Err(Failed::transient(format_err!(
+                "connection closed after {} of {} bytes",
+                offset - start,
+                end - start
+            )));
+        }
         Ok(())
     }
+
+    /// One attempt at downloading a file into its part file, carrying on
+    /// from whatever earlier attempts left there. Returns the part file size.
+    async fn fetch_part(
+        &self,
+        account: &Account,
+        file: &DataFile,
+        part: &Path,
+        progress: &FileProgress<'_>,
+    ) -> Result<u64, Failed> {
+        let expected_size = file.size as u64;
+        let downloaded = fs::metadata(part)
+            .await
+            .map_or(0, |metadata| metadata.len());
+
+        let mut request = self
+            .client
+            .get(format!("{}/files/{}/content", account.url, file.id))
+            .header("x-access-token", &account.token);
+        if downloaded > 0 {
+            info!("Resuming {} from byte {}", file.name, downloaded);
+            request = request.header(RANGE, format!("bytes={}-", downloaded));
+        }
+        let read_timeout = self.http.read_timeout();
+        let mut resp = http::send_timeout(request, read_timeout).await?;
+
+        // If the server ignored the range we get the whole file back
+        let writer = if resp.status() == StatusCode::PARTIAL_CONTENT {
+            OpenOptions::new().append(true).open(part).await
+        } else {
+            progress.restart(downloaded);
+            File::create(part).await
+        }
+        .map_err(Failed::fatal)?;
+
+        // Chunks are gathered in one large buffer, so the file
+        // is written in big blocks rather than per network read
+        let mut writer = BufWriter::with_capacity(DOWNLOAD_BUFFER_SIZE, writer);
+        let received = loop {
+            match http::next_chunk(&mut resp, read_timeout).await {
+                Ok(Some(chunk)) => {
+                    self.throttle(account, chunk.len()).await;
+                    progress.inc(chunk.len() as u64);
+                    writer.write_all(&chunk).await.map_err(Failed::fatal)?
+                }
+                Ok(None) => break Ok(()),
+                Err(failed) => break Err(failed),
+            }
+        };
+        // Whatever was received is kept for the next attempt
+        writer.flush().await.map_err(Failed::fatal)?;
+        received?;
+
+        let downloaded = fs::metadata(part).await.map_err(Failed::fatal)?.len();
+        if downloaded < expected_size {
+            return Err(Failed::transient(format_err!(
+                "connection closed after {} of {} bytes",
+                downloaded,
+                expected_size
+            )));
+        }
+        Ok(downloaded)
+    }
+}
+
+/// The server answered a range request with the whole file
+#[derive(Debug)]
+struct RangesUnsupported;
+
+impl fmt::Display for RangesUnsupported {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the server does not support range requests")
+    }
+}
+
+impl std::error::Error for RangesUnsupported {}
+
+/// The first error of a type in the chain, looking past the context
+/// added when giving up on retries
+fn find_cause<T: failure::Fail>(error: &failure::Error) -> Option<&T> {
+    error
+        .iter_chain()
+        .find_map(|cause| cause.downcast_ref::<T>())
+}
+
+/// Bytes of a segment written so far, logged to the segments file
+/// so an interrupted download resumes from there
+struct Received<'a> {
+    start: u64,
+    bytes: AtomicU64,
+    log: &'a Mutex<std::fs::File>,
+}
+
+impl Received<'_> {
+    fn load(&self) -> u64 {
+        self.bytes.load(Ordering::SeqCst)
+    }
+
+    /// Only called once the bytes are written to the part file
+    fn store(&self, bytes: u64) -> Result<(), Failed> {
+        self.bytes.store(bytes, Ordering::SeqCst);
+        writeln!(self.log.lock().unwrap(), "{} {}", self.start, bytes).map_err(Failed::fatal)
+    }
+}
+
+fn is_broken_pipe(error: &failure::Error) -> bool {
+    matches!(
+        find_cause::<std::io::Error>(error),
+        Some(e) if e.kind() == std::io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Write a buffer at an offset of a file shared between tasks,
+/// handing the buffer back so it can be reused
+async fn write_at(
+    file: &Arc<std::fs::File>,
+    buffer: Vec<u8>,
+    offset: u64,
+) -> Result<Vec<u8>, Failed> {
+    let file = Arc::clone(file);
+    tokio::task::spawn_blocking(move || util::write_all_at(&file, &buffer, offset).map(|_| buffer))
+        .await
+        .map_err(Failed::fatal)?
+        .map_err(Failed::fatal)
 }
@@ -1,6 +1,10 @@
-use basespace_dl::util;
+use basespace_dl::api::{DataFile, Project, Run, Sample};
+use basespace_dl::layout::{self, Collisions, Layout, Origin};
+use basespace_dl::rate::{self, RateLimiter};
+use basespace_dl::util::{self, FileStatus};
 use basespace_dl::workspace::Workspace;
-use clap::{App, Arg, ArgMatches};
+use basespace_dl::{Account, Existing, FetchFailure, Fetched, MultiApi};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use console::style;
 use failure::bail;
 use failure::ResultExt;
@@ -13,17 +17,58 @@ use std::io::{Read, Write};
 use std::path::PathBuf;
 use tabwriter::TabWriter;
 
+/// Arguments for picking a project and its samples, shared
+/// by downloading and the verify subcommand
+fn project_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("project")
+            .index(1)
+            .required(true)
+            .takes_value(true)
+            .help("Project name (e.g. project17890), or run with --run. Use ALL to print all projects"),
+        Arg::with_name("directory")
+            .long("directory")
+            .short("d")
+            .takes_value(true)
+            .help("Download files to (or verify files in) this directory."),
+        Arg::with_name("undetermined")
+            .long("undetermined")
+            .short("U")
+            .required(false)
+            .takes_value(false)
+            .help("Fetch undetermined files as well. These are stored in the \"Unindexed Reads\" project."),
+        Arg::with_name("skip-completion-check")
+            .long("skip-completion-check")
+            .required(false)
+            .takes_value(false)
+            .help("Skip the requirement that all samples in a project be finished processing"),
+        Arg::with_name("layout")
+            .long("layout")
+            .takes_value(true)
+            .value_name("TEMPLATE")
+            .help("Where to save (or look for) each file under the output directory, e.g. \"{project}/{sample}/{file}\". \
+                   Placeholders: {project}, {project_id}, {project_owner}, {sample}, {sample_id}, \
+                   {experiment_name}, {file} and {file_id}. [default: {file}]"),
+        Arg::with_name("on-collision")
+            .long("on-collision")
+            .takes_value(true)
+            .value_name("POLICY")
+            .possible_values(&["error", "suffix", "nest"])
+            .default_value("error")
+            .help("What to do when files from different samples would be saved to the same path: \
+                   refuse to download, add the sample ID to their names, or put them in folders \
+                   named after the sample ID"),
+    ]
+}
+
 fn build_app() -> App<'static, 'static> {
     App::new("basespace-dl")
         .version(env!("CARGO_PKG_VERSION"))
         .author("dweb0")
         .about("Multi-account basespace file downloader")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .args(&project_args())
         .args(&[
-            Arg::with_name("project")
-                .index(1)
-                .required(true)
-                .takes_value(true)
-                .help("Project name (e.g. project17890). Use ALL to print all projects"),
             Arg::with_name("list-files")
                 .long("list-files")
                 .short("F")
@@ -39,38 +84,147 @@ fn build_app() -> App<'static, 'static> {
                 .short("f")
                 .takes_value(true)
                 .help("Only select files from this list. Accepts a file or - for STDIN."),
-            Arg::with_name("directory")
-                .long("directory")
-                .short("d")
-                .takes_value(true)
-                .help("Download files to this directory."),
-            Arg::with_name("undetermined")
-                .long("undetermined")
-                .short("U")
-                .required(false)
+            Arg::with_name("run")
+                .long("run")
+                .short("r")
+                .takes_value(false)
+                .conflicts_with("undetermined")
+                .help("Select a sequencing run (by name or flowcell ID) instead of a project. Use ALL to print all runs"),
+            Arg::with_name("run-samples")
+                .long("run-samples")
                 .takes_value(false)
-                .help("Fetch undetermined files as well. These are stored in the \"Unindexed Reads\" project."),
+                .requires("run")
+                .help("Fetch the demultiplexed samples of the run instead of its output files"),
             Arg::with_name("config")
                 .long("config")
                 .short("C")
                 .takes_value(true)
+                .global(true)
                 .help("Alternate config. Stored in $HOME/.config/basespace-dl/{name}.toml"),
-            Arg::with_name("skip-completion-check")
-                .long("skip-completion-check")
-                .required(false)
-                .takes_value(false)
-                .help("Skip the requirement that all samples in a project be finished processing"),
             Arg::with_name("long-format")
                 .long("long-format")
                 .short("l")
                 .takes_value(false)
                 .help("Long format. Prints file size if listing files or more project info if listing projects"),
+            Arg::with_name("jobs")
+                .long("jobs")
+                .short("j")
+                .takes_value(true)
+                .value_name("N")
+                .help("Number of files to download at the same time [default: 8]"),
+            Arg::with_name("limit-rate")
+                .long("limit-rate")
+                .takes_value(true)
+                .value_name("RATE")
+                .help("Cap the bandwidth of all downloads together, in bytes per second. \
+                       Accepts K, M and G suffixes, e.g. 50M"),
+            Arg::with_name("segments")
+                .long("segments")
+                .takes_value(true)
+                .value_name("N")
+                .help("Number of byte ranges of each file over 256 MiB to download at the same time. \
+                       1 downloads large files as one stream. [default: 4]"),
+            Arg::with_name("cat")
+                .long("cat")
+                .takes_value(true)
+                .min_values(0)
+                .max_values(1)
+                .value_name("FILE")
+                .conflicts_with("list-files")
+                .help("Write one file to stdout instead of downloading, e.g. to pipe it into zcat. \
+                       The file is picked by name, or by -p and -f, which must select exactly one."),
+            Arg::with_name("on-existing")
+                .long("on-existing")
+                .takes_value(true)
+                .value_name("POLICY")
+                .possible_values(&["skip", "overwrite", "rename", "fail"])
+                .default_value("fail")
+                .help("What to do with a local file that differs from the remote file: keep it, \
+                       replace it, move it to {name}.1 before downloading, or report an error. \
+                       Files that match are always skipped."),
+            Arg::with_name("force")
+                .long("force")
+                .takes_value(false)
+                .help("Download even if there doesn't seem to be enough free space"),
+            Arg::with_name("allow-partial")
+                .long("allow-partial")
+                .required(false)
+                .takes_value(false)
+                .global(true)
+                .help("Continue even if some accounts or samples could not be fetched"),
             Arg::with_name("verbose")
                 .long("verbose")
                 .short("v")
                 .required(false)
+                .global(true)
                 .help("Print status messages"),
+            Arg::with_name("refresh")
+                .long("refresh")
+                .required(false)
+                .global(true)
+                .help("Ignore cached listings and fetch them again"),
+            Arg::with_name("offline")
+                .long("offline")
+                .required(false)
+                .global(true)
+                .conflicts_with("refresh")
+                .help("Only list from the cache, without touching the network"),
+            Arg::with_name("retries")
+                .long("retries")
+                .takes_value(true)
+                .value_name("N")
+                .global(true)
+                .help("Times to retry a request that failed with a 429, 5xx or dropped connection"),
         ])
+        .subcommand(
+            SubCommand::with_name("account")
+                .about("Manage the accounts in the config file")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Add an account. The user ID is looked up from the token.")
+                        .args(&[
+                            Arg::with_name("token")
+                                .index(1)
+                                .required(true)
+                                .takes_value(true)
+                                .help("Access token from the basespace developer dashboard"),
+                            Arg::with_name("url")
+                                .long("url")
+                                .takes_value(true)
+                                .help("API url, for enterprise or regional basespace instances"),
+                        ]),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Remove an account")
+                        .arg(
+                            Arg::with_name("user-id")
+                                .index(1)
+                                .required(true)
+                                .takes_value(true)
+                                .help("User ID of the account"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("list").about("List the accounts in the config file"),
+                )
+                .subcommand(
+                    SubCommand::with_name("test")
+                        .about("Check that the token of every account still works"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cache")
+                .about("Manage the cache of project, sample and file listings")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("clear").about("Remove every cached listing")),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check a local directory against a project without downloading anything")
+                .args(&project_args()),
+        )
 }
 
 #[tokio::main]
@@ -91,59 +245,40 @@ async fn main() {
     }
 }
 
-async fn real_main(matches: ArgMatches<'static>) -> Result<(), failure::Error> {
-    let ws = match matches.value_of("config") {
-        Some(config) => Workspace::with_config(config),
-        None => Workspace::new(),
+/// Warn about every request that failed while listing. Unless the user
+/// accepts partial results, any failure is an error.
+fn check_failures(
+    failures: &[FetchFailure],
+    what: &str,
+    allow_partial: bool,
+) -> Result<(), failure::Error> {
+    if failures.is_empty() {
+        return Ok(());
     }
-    .with_context(|e| format!("Could not generate workspace. {}", e))?;
-
-    let directory = match matches.value_of("directory") {
-        Some(dir) => {
-            let path_dir = PathBuf::from(dir);
-            if !path_dir.is_dir() {
-                bail!("{} is not a valid directory", dir);
-            }
-            path_dir
-        }
-        None => PathBuf::from("."),
-    };
-
-    let multi = ws
-        .to_multiapi()
-        .with_context(|e| format!("Could not generate multi-api from workspace. {}", e))?;
-    let query = matches.value_of("project").unwrap();
-    let projects = multi.get_projects().await?;
 
-    if query == "ALL" {
-        if matches.is_present("long-format") {
-            
-            // Only print YYYY-MM-DD and not timestamp
-            // Timestamp should ALWAYS include this
-            let date_re = Regex::new(r"^\d{4,4}-\d{2,2}-\d{2,2}").unwrap();
+    for failure in failures {
+        eprintln!(
+            "{} Could not fetch {} for {}",
+            style("warning:").bold().yellow(),
+            what,
+            failure
+        );
+    }
 
-            for project in projects {
-                let date = match date_re.find(&project.date_created) {
-                    Some(mat) => mat.as_str(),
-                    None => ""
-                };
-                
-                println!(
-                    "{},{},{},{}",
-                    project.name,
-                    project.user_owned_by.id,
-                    project.user_owned_by.name,
-                    date,
-                );
-            }
-        } else {
-            for project in projects {
-                println!("{}", project.name);
-            }
-        }
-        std::process::exit(0);
+    if !allow_partial {
+        bail!(
+            "{} request(s) for {} failed, results are incomplete. \
+             Use --allow-partial to continue anyway.",
+            failures.len(),
+            what
+        );
     }
+    Ok(())
+}
 
+/// Find the project matching the query, asking the user
+/// to pick one if there are several with the same name.
+fn find_project<'a>(query: &str, projects: &'a [Project]) -> Result<&'a Project, failure::Error> {
     let mut matching_projects: Vec<_> = projects.iter().filter(|p| p.name == query).collect();
     let project = if matching_projects.is_empty() {
         let candidates = util::did_you_mean(query, projects);
@@ -160,7 +295,17 @@ async fn real_main(matches: ArgMatches<'static>) -> Result<(), failure::Error> {
     } else {
         matching_projects.remove(0)
     };
+    Ok(project)
+}
 
+/// Fetch the completed samples of a project, along with
+/// its Undetermined sample if requested.
+async fn get_samples(
+    multi: &MultiApi,
+    project: &Project,
+    projects: &[Project],
+    matches: &ArgMatches<'static>,
+) -> Result<Vec<Sample>, failure::Error> {
     let samples = if matches.is_present("undetermined") {
         if project.user_fetched_by_id != project.user_owned_by.id {
             bail!("Must be the owner of a project to access its \"Unindexed Reads\".");
@@ -188,6 +333,15 @@ async fn real_main(matches: ArgMatches<'static>) -> Result<(), failure::Error> {
         multi.get_samples(project).await?
     };
 
+    completed_samples(samples, matches)
+}
+
+/// Only keep samples that have finished processing. Unless the user
+/// asked to skip the check, unfinished samples are an error.
+fn completed_samples(
+    samples: Vec<Sample>,
+    matches: &ArgMatches<'static>,
+) -> Result<Vec<Sample>, failure::Error> {
     let (samples, unfinished_samples): (Vec<_>, Vec<_>) =
         samples.into_iter().partition(|s| s.status == "Complete");
 
@@ -199,22 +353,426 @@ async fn real_main(matches: ArgMatches<'static>) -> Result<(), failure::Error> {
             );
         } else {
             bail!(
-                "Project not finished yet. {} samples still processing.",
+                "Not finished yet. {} samples still processing.",
                 unfinished_samples.len()
             );
         }
     }
 
     info!("Found {} completed samples", samples.len());
+    Ok(samples)
+}
+
+/// Find the runs matching the query by name, experiment
+/// name or flowcell ID, asking the user to pick one if needed.
+fn find_run<'a>(query: &str, runs: &'a [Run]) -> Result<&'a Run, failure::Error> {
+    let mut matching_runs: Vec<_> = runs.iter().filter(|r| r.matches(query)).collect();
+    let run = if matching_runs.is_empty() {
+        let candidates = util::did_you_mean(query, runs);
+        if candidates.is_empty() {
+            bail!("no such run {}.", query);
+        }
+        bail!(
+            "no such run {}. Did you mean one of these?\n\n{}",
+            query,
+            candidates.join("\n")
+        );
+    } else if matching_runs.len() > 1 {
+        util::resolve_duplicate_runs(matching_runs)
+    } else {
+        matching_runs.remove(0)
+    };
+    Ok(run)
+}
+
+/// Only print YYYY-MM-DD and not timestamp
+fn short_date(date: &str) -> &str {
+    // Timestamp should ALWAYS include this
+    let date_re = Regex::new(r"^\d{4,4}-\d{2,2}-\d{2,2}").unwrap();
+    match date_re.find(date) {
+        Some(mat) => mat.as_str(),
+        None => "",
+    }
+}
+
+fn output_directory(matches: &ArgMatches<'static>) -> Result<PathBuf, failure::Error> {
+    let directory = match matches.value_of("directory") {
+        Some(dir) => {
+            let path_dir = PathBuf::from(dir);
+            if !path_dir.is_dir() {
+                bail!("{} is not a valid directory", dir);
+            }
+            path_dir
+        }
+        None => PathBuf::from("."),
+    };
+    Ok(directory)
+}
+
+async fn real_main(matches: ArgMatches<'static>) -> Result<(), failure::Error> {
+    let ws = match matches.value_of("config") {
+        Some(config) => Workspace::with_config(config),
+        None => Workspace::new(),
+    }
+    .with_context(|e| format!("Could not generate workspace. {}", e))?;
+
+    // Accounts can be managed before there are any in the config
+    match matches.subcommand() {
+        ("account", Some(sub_matches)) => return account(&ws, sub_matches).await,
+        ("cache", Some(sub_matches)) => return cache(&ws, sub_matches),
+        _ => {}
+    }
+
+    let mut multi = ws
+        .to_multiapi()
+        .with_context(|e| format!("Could not generate multi-api from workspace. {}", e))?;
+    if let Some(cache) = multi.cache.as_mut() {
+        cache.refresh = matches.is_present("refresh");
+    }
+    multi.offline = matches.is_present("offline");
+    if let Some(retries) = matches.value_of("retries") {
+        multi.retry.retries = retries
+            .parse::<u32>()
+            .with_context(|e| format!("Invalid --retries {}. {}", retries, e))?;
+    }
+    if let Some(jobs) = matches.value_of("jobs") {
+        multi.jobs = jobs
+            .parse::<usize>()
+            .with_context(|e| format!("Invalid --jobs {}. {}", jobs, e))?;
+        if multi.jobs == 0 {
+            bail!("--jobs must be at least 1");
+        }
+    }
+    if let Some(rate) = matches.value_of("limit-rate") {
+        multi.rate_limit = Some(RateLimiter::new(rate::parse_rate(rate)?));
+    }
+    if let Some(segments) = matches.value_of("segments") {
+        multi.segments = segments
+            .parse::<usize>()
+            .with_context(|e| format!("Invalid --segments {}. {}", segments, e))?;
+        if multi.segments == 0 {
+            bail!("--segments must be at least 1");
+        }
+    }
+
+    multi.force = matches.is_present("force");
+    multi.on_existing = match matches.value_of("on-existing") {
+        Some("skip") => Existing::Skip,
+        Some("overwrite") => Existing::Overwrite,
+        Some("rename") => Existing::Rename,
+        _ => Existing::Fail,
+    };
+
+    match matches.subcommand() {
+        ("verify", Some(sub_matches)) => verify(&multi, sub_matches).await,
+        _ => download(&multi, &matches).await,
+    }
+}
+
+async fn account(ws: &Workspace, matches: &ArgMatches<'static>) -> Result<(), failure::Error> {
+    if let ("add", _) | ("test", _) = matches.subcommand() {
+        if matches.is_present("offline") {
+            bail!("Checking tokens needs the network, so it can't be done with --offline.");
+        }
+    }
+
+    match matches.subcommand() {
+        ("add", Some(matches)) => {
+            let token = matches.value_of("token").unwrap();
+            let url = matches.value_of("url");
+            let http = ws.http_settings()?;
+            let user = Account::new(token, url)
+                .current_user(&http.client()?, http.read_timeout())
+                .await
+                .with_context(|e| format!("Could not validate token. {}", e))?;
+
+            ws.add_account(&user.id, token, url)?;
+            eprintln!(
+                "{} Added account {} ({}) to {}",
+                style("success:").bold().green(),
+                user.id,
+                user.name,
+                ws.config_file.display()
+            );
+        }
+        ("remove", Some(matches)) => {
+            let user_id = matches.value_of("user-id").unwrap();
+            ws.remove_account(user_id)?;
+            eprintln!(
+                "{} Removed account {} from {}",
+                style("success:").bold().green(),
+                user_id,
+                ws.config_file.display()
+            );
+        }
+        ("list", Some(_)) => {
+            let mut accounts: Vec<_> = ws.accounts()?.into_iter().collect();
+            accounts.sort_by(|a, b| a.0.cmp(&b.0));
+
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            let mut writer = TabWriter::new(&mut stdout);
+            for (user_id, account) in accounts {
+                writeln!(&mut writer, "{}\t{}", user_id, account.url)?;
+            }
+            writer.flush()?;
+        }
+        ("test", Some(_)) => {
+            let mut accounts: Vec<_> = ws.accounts()?.into_iter().collect();
+            accounts.sort_by(|a, b| a.0.cmp(&b.0));
+
+            let http = ws.http_settings()?;
+            let client = http.client()?;
+            let users = future::join_all(
+                accounts
+                    .iter()
+                    .map(|(_, account)| account.current_user(&client, http.read_timeout())),
+            )
+            .await;
+
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            let mut writer = TabWriter::new(&mut stdout);
+            let mut failed = 0;
+            for ((user_id, _), user) in accounts.iter().zip(users) {
+                match user {
+                    Ok(user) if &user.id == user_id => {
+                        writeln!(&mut writer, "{}\t{}\tok", user_id, user.name)?;
+                    }
+                    Ok(user) => {
+                        failed += 1;
+                        writeln!(
+                            &mut writer,
+                            "{}\t{}\ttoken belongs to user {}",
+                            user_id, user.name, user.id
+                        )?;
+                    }
+                    Err(e) => {
+                        failed += 1;
+                        let failure = FetchFailure::new(user_id, None, e);
+                        let reason = match failure.status {
+                            Some(status) => format!("HTTP {}", status),
+                            None => failure.error.to_string(),
+                        };
+                        writeln!(&mut writer, "{}\t\tfailed ({})", user_id, reason)?;
+                    }
+                }
+            }
+            writer.flush()?;
+
+            if failed > 0 {
+                bail!("{} of {} accounts failed.", failed, accounts.len());
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Manage the cache of listings
+fn cache(ws: &Workspace, matches: &ArgMatches<'static>) -> Result<(), failure::Error> {
+    let cache = ws.cache()?;
+    if let ("clear", _) = matches.subcommand() {
+        cache.clear()?;
+        eprintln!(
+            "{} Cleared cache in {}",
+            style("success:").bold().green(),
+            cache.dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// Compare a local directory with the files of a project
+async fn verify(multi: &MultiApi, matches: &ArgMatches<'static>) -> Result<(), failure::Error> {
+    let directory = output_directory(matches)?;
+    let query = matches.value_of("project").unwrap();
+    let allow_partial = matches.is_present("allow-partial");
+
+    let fetched = multi.get_projects().await?;
+    check_failures(&fetched.failures, "projects", allow_partial)?;
+    let projects = fetched.items;
+    let project = find_project(query, &projects)?;
+    let samples = get_samples(multi, project, &projects, matches).await?;
+
+    // An incomplete listing would report local files as extra
+    let fetched = multi
+        .get_files(&project.user_fetched_by_id, &samples)
+        .await?;
+    check_failures(&fetched.failures, "files", allow_partial)?;
+    let mut files = fetched.items;
+    place_files(&mut files, &project.into(), matches)?;
+
+    info!(
+        "Verifying {} files in {}...",
+        files.len(),
+        directory.display()
+    );
+    let statuses = util::verify_directory(&files, &directory)?;
+    let problems: Vec<_> = statuses
+        .iter()
+        .filter(|(status, _)| *status != FileStatus::Ok)
+        .collect();
+
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let mut writer = TabWriter::new(&mut stdout);
+    for (status, name) in &problems {
+        writeln!(&mut writer, "{}\t{}", status, name)?;
+    }
+    writer.flush()?;
+
+    if !problems.is_empty() {
+        bail!(
+            "{} problems found while verifying {} files in {}",
+            problems.len(),
+            files.len(),
+            directory.display()
+        );
+    }
+
+    eprintln!(
+        "{} All {} files in {} match the project",
+        style("success:").bold().green(),
+        files.len(),
+        directory.display()
+    );
+    Ok(())
+}
+
+async fn download(multi: &MultiApi, matches: &ArgMatches<'static>) -> Result<(), failure::Error> {
+    if matches.is_present("run") {
+        return download_run(multi, matches).await;
+    }
+
+    let query = matches.value_of("project").unwrap();
+    let allow_partial = matches.is_present("allow-partial");
+    let fetched = multi.get_projects().await?;
+    let projects = fetched.items;
+
+    if query == "ALL" {
+        if matches.is_present("long-format") {
+            for project in projects {
+                println!(
+                    "{},{},{},{}",
+                    project.name,
+                    project.user_owned_by.id,
+                    project.user_owned_by.name,
+                    short_date(&project.date_created),
+                );
+            }
+        } else {
+            for project in projects {
+                println!("{}", project.name);
+            }
+        }
+        return check_failures(&fetched.failures, "projects", allow_partial);
+    }
+
+    check_failures(&fetched.failures, "projects", allow_partial)?;
+
+    let project = find_project(query, &projects)?;
+    let samples = get_samples(multi, project, &projects, matches).await?;
+
     info!("Fetching files...");
 
-    let mut files = multi.get_files(project, &samples).await?;
+    let fetched = multi
+        .get_files(&project.user_fetched_by_id, &samples)
+        .await?;
+    select_and_download(
+        multi,
+        &project.user_fetched_by_id,
+        project.into(),
+        fetched,
+        matches,
+    )
+    .await
+}
+
+/// Same as downloading a project, but starting from a sequencing run.
+/// Either the run's own output files, or the files of its samples.
+async fn download_run(
+    multi: &MultiApi,
+    matches: &ArgMatches<'static>,
+) -> Result<(), failure::Error> {
+    let query = matches.value_of("project").unwrap();
+    let allow_partial = matches.is_present("allow-partial");
+    let fetched = multi.get_runs().await?;
+    let runs = fetched.items;
+
+    if query == "ALL" {
+        if matches.is_present("long-format") {
+            for run in runs {
+                println!(
+                    "{},{},{},{},{}",
+                    run.name,
+                    run.flowcell_id().unwrap_or(""),
+                    run.user_owned_by.id,
+                    run.user_owned_by.name,
+                    short_date(&run.date_created),
+                );
+            }
+        } else {
+            for run in runs {
+                println!("{}", run.name);
+            }
+        }
+        return check_failures(&fetched.failures, "runs", allow_partial);
+    }
+
+    check_failures(&fetched.failures, "runs", allow_partial)?;
+
+    let run = find_run(query, &runs)?;
+    info!("Fetching files...");
+
+    let fetched = if matches.is_present("run-samples") {
+        let samples = completed_samples(multi.get_run_samples(run).await?, matches)?;
+        multi.get_files(&run.user_fetched_by_id, &samples).await?
+    } else {
+        Fetched {
+            items: multi.get_run_files(run).await?,
+            failures: vec![],
+        }
+    };
+    select_and_download(multi, &run.user_fetched_by_id, run.into(), fetched, matches).await
+}
+
+/// Work out where each file goes under the output directory,
+/// following --layout and --on-collision
+fn place_files(
+    files: &mut [DataFile],
+    origin: &Origin,
+    matches: &ArgMatches<'static>,
+) -> Result<(), failure::Error> {
+    if let Some(template) = matches.value_of("layout") {
+        let layout = Layout::parse(template)?;
+        for file in files.iter_mut() {
+            file.destination = Some(layout.render(origin, file)?);
+        }
+    }
+    let collisions = match matches.value_of("on-collision") {
+        Some("suffix") => Collisions::Suffix,
+        Some("nest") => Collisions::Nest,
+        _ => Collisions::Error,
+    };
+    layout::resolve_collisions(files, collisions)
+}
+
+/// Apply the pattern and file list filters, then either list
+/// the files or download them where the layout puts them.
+async fn select_and_download(
+    multi: &MultiApi,
+    account_id: &str,
+    origin: Origin<'_>,
+    fetched: Fetched<DataFile>,
+    matches: &ArgMatches<'static>,
+) -> Result<(), failure::Error> {
+    let directory = output_directory(matches)?;
+    let allow_partial = matches.is_present("allow-partial");
+    let mut files = fetched.items;
     if let Some(pattern) = matches.value_of("pattern") {
         let re = Regex::new(pattern).with_context(|e| format!("Invalid regex pattern. {}", e))?;
-        files = files
-            .into_iter()
-            .filter(|file| re.find(&file.name).is_some())
-            .collect();
+        files.retain(|file| re.find(&file.name).is_some());
     }
 
     if let Some(filelist) = matches.value_of("select-files") {
@@ -225,10 +783,7 @@ async fn real_main(matches: ArgMatches<'static>) -> Result<(), failure::Error> {
         let mut buffer = String::new();
         rdr.read_to_string(&mut buffer)?;
         let filter_list: HashSet<String> = buffer.lines().map(|line| line.to_owned()).collect();
-        files = files
-            .into_iter()
-            .filter(|file| filter_list.contains(&file.name))
-            .collect();
+        files.retain(|file| filter_list.contains(&file.name));
     }
 
     if matches.is_present("list-files") {
@@ -256,13 +811,51 @@ async fn real_main(matches: ArgMatches<'static>) -> Result<(), failure::Error> {
                 });
             }
         }
-        std::process::exit(0);
+        return check_failures(&fetched.failures, "files", allow_partial);
     }
 
+    check_failures(&fetched.failures, "files", allow_partial)?;
+
+    // Before the layout is checked, which doesn't matter if nothing can be downloaded
+    if multi.offline {
+        bail!("Cannot download in offline mode. Use -F to list the files instead.");
+    }
+
+    if matches.is_present("cat") {
+        if let Some(name) = matches.value_of("cat") {
+            files.retain(|file| file.name == name);
+        }
+        let file = match files.as_slice() {
+            [file] => file,
+            [] => bail!("No file selected for --cat"),
+            _ => {
+                let names: Vec<&str> = files
+                    .iter()
+                    .take(5)
+                    .map(|file| file.name.as_str())
+                    .collect();
+                bail!(
+                    "--cat needs exactly one file, but {} were selected: {}{}",
+                    files.len(),
+                    names.join(", "),
+                    if files.len() > names.len() {
+                        ", ..."
+                    } else {
+                        ""
+                    }
+                );
+            }
+        };
+        return multi.cat_file(file, account_id).await;
+    }
+
+    place_files(&mut files, &origin, matches)?;
+
     info!("Downloading {} files...", files.len());
 
     multi
-        .download_files(&files, project, directory)
+        .download_files(&files, account_id, directory)
+        .await
         .with_context(|e| format!("Could not download files. {}", e))?;
 
     Ok(())
@@ -0,0 +1,219 @@
+//! Download progress: an overall bar of bytes with speed and ETA, and
+//! a bar for each file being downloaded. When stderr is not a terminal
+//! (a log file, a cluster job), a plain line is printed every so often.
+//!
+//! Bytes that were already on disk from an earlier run are taken off
+//! the total rather than counted, so they don't inflate the speed.
+use crate::util;
+use indicatif::{HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often the plain text fallback prints a line
+pub static PLAIN_INTERVAL: Duration = Duration::from_secs(10);
+
+enum Display {
+    Bars {
+        multi: MultiProgress,
+        total: ProgressBar,
+    },
+    Plain {
+        last_line: Mutex<Instant>,
+    },
+}
+
+pub struct Progress {
+    display: Display,
+    num_files: usize,
+    files_done: AtomicUsize,
+    /// Bytes to download in this run
+    total_bytes: AtomicU64,
+    done_bytes: AtomicU64,
+    started: Instant,
+}
+
+impl Progress {
+    /// Bars if stderr is a terminal, plain lines otherwise
+    pub fn new(num_files: usize, total_bytes: u64) -> Progress {
+        let display = if console::user_attended_stderr() {
+            let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+            let total = multi.add(ProgressBar::new(total_bytes));
+            total.set_style(
+                ProgressStyle::with_template(
+                    "{prefix:>11} [{elapsed_precise}] [{wide_bar:.cyan/blue}] \
+                     {bytes}/{total_bytes} {bytes_per_sec} ETA {eta}",
+                )
+                .unwrap()
+                .progress_chars("#>-"),
+            );
+            total.set_prefix(format!("0/{} files", num_files));
+            Display::Bars { multi, total }
+        } else {
+            Display::Plain {
+                last_line: Mutex::new(Instant::now()),
+            }
+        };
+
+        Progress {
+            display,
+            num_files,
+            files_done: AtomicUsize::new(0),
+            total_bytes: AtomicU64::new(total_bytes),
+            done_bytes: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    /// Print a line above the bars
+    pub fn println(&self, line: &str) {
+        match &self.display {
+            Display::Bars { multi, .. } => {
+                let _ = multi.println(line);
+            }
+            Display::Plain { .. } => eprintln!("{}", line),
+        }
+    }
+
+    /// Add a bar for a file that is starting to download
+    pub fn start_file(&self, name: &str, size: u64) -> FileProgress<'_> {
+        let bar = match &self.display {
+            Display::Bars { multi, .. } => {
+                let bar = multi.add(ProgressBar::new(size));
+                bar.set_style(
+                    ProgressStyle::with_template(
+                        "{prefix:>11} {wide_msg} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec}",
+                    )
+                    .unwrap()
+                    .progress_chars("=> "),
+                );
+                bar.set_message(name.to_owned());
+                Some(bar)
+            }
+            Display::Plain { .. } => None,
+        };
+        FileProgress {
+            progress: self,
+            bar,
+        }
+    }
+
+    pub fn finish(&self) {
+        if let Display::Bars { multi, total } = &self.display {
+            total.finish_and_clear();
+            let _ = multi.clear();
+        }
+    }
+
+    fn add(&self, bytes: u64) {
+        self.done_bytes.fetch_add(bytes, Ordering::SeqCst);
+        match &self.display {
+            Display::Bars { total, .. } => total.inc(bytes),
+            Display::Plain { last_line } => {
+                let mut last_line = last_line.lock().unwrap();
+                if last_line.elapsed() >= PLAIN_INTERVAL {
+                    *last_line = Instant::now();
+                    eprintln!("{}", self.summary());
+                }
+            }
+        }
+    }
+
+    /// Change how many bytes there are to download in this run
+    fn update_total(&self, update: impl Fn(u64) -> u64) {
+        let previous = self
+            .total_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |bytes| {
+                Some(update(bytes))
+            })
+            .unwrap();
+        if let Display::Bars { total, .. } = &self.display {
+            total.set_length(update(previous));
+        }
+    }
+
+    fn file_done(&self) {
+        let files_done = self.files_done.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Display::Bars { total, .. } = &self.display {
+            total.set_prefix(format!("{}/{} files", files_done, self.num_files));
+        }
+    }
+
+    /// e.g. "3/10 files, 1.2G of 4.0G (30%) at 48M/s, 58 seconds left"
+    fn summary(&self) -> String {
+        let done = self.done_bytes.load(Ordering::SeqCst);
+        let total = self.total_bytes.load(Ordering::SeqCst).max(1);
+        let speed = done as f64 / self.started.elapsed().as_secs_f64().max(0.001);
+        let left = if speed > 0.0 {
+            HumanDuration(Duration::from_secs_f64(
+                total.saturating_sub(done) as f64 / speed,
+            ))
+            .to_string()
+        } else {
+            String::from("unknown time")
+        };
+        format!(
+            "{}/{} files, {} of {} ({}%) at {}/s, {} left",
+            self.files_done.load(Ordering::SeqCst),
+            self.num_files,
+            util::convert_bytes(done as f64),
+            util::convert_bytes(total as f64),
+            done * 100 / total,
+            util::convert_bytes(speed),
+            left
+        )
+    }
+}
+
+/// Progress of one file. The bar goes away when this is dropped.
+pub struct FileProgress<'a> {
+    progress: &'a Progress,
+    bar: Option<ProgressBar>,
+}
+
+impl FileProgress<'_> {
+    /// Bytes received
+    pub fn inc(&self, bytes: u64) {
+        if let Some(bar) = &self.bar {
+            bar.inc(bytes);
+        }
+        self.progress.add(bytes);
+    }
+
+    /// Bytes an earlier run left on disk, which don't need downloading
+    pub fn resume(&self, bytes: u64) {
+        if let Some(bar) = &self.bar {
+            bar.set_position(bytes);
+            bar.reset_eta();
+        }
+        self.progress
+            .update_total(|total| total.saturating_sub(bytes));
+    }
+
+    /// The file is starting over, so bytes already
+    /// counted for it have to be downloaded again
+    pub fn restart(&self, bytes: u64) {
+        if let Some(bar) = &self.bar {
+            bar.set_position(0);
+            bar.reset_eta();
+        }
+        self.progress.update_total(|total| total + bytes);
+    }
+
+    /// Say what is happening once all bytes are in, e.g. "verifying"
+    pub fn set_status(&self, status: &'static str) {
+        if let Some(bar) = &self.bar {
+            bar.set_prefix(status);
+        }
+    }
+}
+
+impl Drop for FileProgress<'_> {
+    fn drop(&mut self) {
+        if let (Some(bar), Display::Bars { multi, .. }) = (&self.bar, &self.progress.display) {
+            bar.finish_and_clear();
+            multi.remove(bar);
+        }
+        self.progress.file_done();
+    }
+}
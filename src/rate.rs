@@ -0,0 +1,76 @@
+//! Bandwidth limits. Every download shares one token bucket, so the
+//! limit holds for all of them together however many run at once.
+use failure::bail;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Bytes per second
+    rate: f64,
+    /// Most bytes that can be sent at once after a pause
+    capacity: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> RateLimiter {
+        let rate = bytes_per_second.max(1) as f64;
+        // A quarter of a second worth of traffic, so the
+        // limit holds even when measured over short spans
+        let capacity = rate / 4.0;
+        RateLimiter {
+            rate,
+            capacity,
+            bucket: Mutex::new(Bucket {
+                tokens: capacity,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `bytes` from the bucket, waiting until they would have been
+    /// available. The bucket may go into debt, so a chunk larger than
+    /// the capacity is let through and paid for by whoever comes next.
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last).as_secs_f64() * self.rate;
+            bucket.tokens = (bucket.tokens + refill).min(self.capacity) - bytes as f64;
+            bucket.last = now;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Parse a rate like curl's --limit-rate: bytes per second, with
+/// an optional K, M or G suffix for KiB, MiB or GiB
+pub fn parse_rate(rate: &str) -> Result<u64, failure::Error> {
+    let rate = rate.trim();
+    let (number, multiplier) = match rate.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&rate[..rate.len() - 1], 1024),
+        Some('M') => (&rate[..rate.len() - 1], 1024 * 1024),
+        Some('G') => (&rate[..rate.len() - 1], 1024 * 1024 * 1024),
+        _ => (rate, 1),
+    };
+    let number: f64 = match number.parse() {
+        Ok(number) => number,
+        Err(_) => bail!("Invalid rate {}. Expected e.g. 500K, 50M or 1G.", rate),
+    };
+    let bytes = (number * multiplier as f64) as u64;
+    if bytes == 0 {
+        bail!("Invalid rate {}. It must be more than 0.", rate);
+    }
+    Ok(bytes)
+}
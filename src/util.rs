@@ -1,8 +1,14 @@
-use super::api::{Project, Sample};
+use super::api::{DataFile, Project, Run, Sample};
 use console::style;
-use failure::bail;
+use failure::{bail, ResultExt};
+use log::{info, warn};
 use number_prefix::NumberPrefix;
-use std::io::{Read, Write};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
 use tabwriter::TabWriter;
 
 /// Return the top 5 closest matching hits for a given query
@@ -29,43 +35,31 @@ where
     candidates.into_iter().take(5).map(|x| x.1).collect()
 }
 
-/// When there are duplicate projects, the user needs
-/// to resolve the conflict. This function prompts the user to pick
-/// the desired project
-pub fn resolve_duplicate_projects(mut projects: Vec<&Project>) -> &Project {
-    eprintln!(
-        "{} Found {} projects with the same name.",
-        style("warning:").bold().yellow(),
-        projects.len()
-    );
-
+/// Print the candidates on stderr as a numbered table, and
+/// prompt the user until they enter a valid index.
+fn prompt_index(noun: &str, candidates: &[(&str, &str)]) -> usize {
     let stderr = std::io::stderr();
     let mut stderr = stderr.lock();
     let mut writer = TabWriter::new(&mut stderr);
 
     writeln!(&mut writer, "#\tname\tdate created").unwrap();
-    for (index, project) in projects.iter().enumerate() {
-        writeln!(
-            &mut writer,
-            "{}\t{}\t{}",
-            index, project.user_owned_by.name, project.date_created
-        )
-        .unwrap();
+    for (index, (name, date_created)) in candidates.iter().enumerate() {
+        writeln!(&mut writer, "{}\t{}\t{}", index, name, date_created).unwrap();
     }
     writer.flush().unwrap();
 
     let invalid_input = format!(
         "{} Please enter an integer from 0 to {}",
         style("error:").bold().red(),
-        projects.len() - 1
+        candidates.len() - 1
     );
 
-    let user_index = loop {
-        eprint!("Enter the project index [0..{}]: ", projects.len() - 1);
+    loop {
+        eprint!("Enter the {} index [0..{}]: ", noun, candidates.len() - 1);
         let response: Result<usize, _> = try_read!();
         break match response {
             Ok(response) => {
-                if response > projects.len() - 1 {
+                if response > candidates.len() - 1 {
                     eprintln!("{}", invalid_input);
                     continue;
                 }
@@ -76,7 +70,29 @@ pub fn resolve_duplicate_projects(mut projects: Vec<&Project>) -> &Project {
                 continue;
             }
         };
-    };
+    }
+}
+
+/// When there are duplicate projects, the user needs
+/// to resolve the conflict. This function prompts the user to pick
+/// the desired project
+pub fn resolve_duplicate_projects(mut projects: Vec<&Project>) -> &Project {
+    eprintln!(
+        "{} Found {} projects with the same name.",
+        style("warning:").bold().yellow(),
+        projects.len()
+    );
+
+    let candidates: Vec<_> = projects
+        .iter()
+        .map(|project| {
+            (
+                project.user_owned_by.name.as_str(),
+                project.date_created.as_str(),
+            )
+        })
+        .collect();
+    let user_index = prompt_index("project", &candidates);
     projects.remove(user_index)
 }
 
@@ -90,146 +106,497 @@ pub fn resolve_duplicate_unindexed_reads(mut samples: Vec<&Sample>) -> &Sample {
         samples.len()
     );
 
-    let stderr = std::io::stderr();
-    let mut stderr = stderr.lock();
-    let mut writer = TabWriter::new(&mut stderr);
-
-    writeln!(&mut writer, "#\tname\tdate created").unwrap();
-    for (index, sample) in samples.iter().enumerate() {
-        writeln!(
-            &mut writer,
-            "{}\t{}\t{}",
-            index, sample.name, sample.date_created
-        )
-        .unwrap();
-    }
-    writer.flush().unwrap();
+    let candidates: Vec<_> = samples
+        .iter()
+        .map(|sample| (sample.name.as_str(), sample.date_created.as_str()))
+        .collect();
+    let user_index = prompt_index("sample", &candidates);
+    samples.remove(user_index)
+}
 
-    let invalid_input = format!(
-        "{} Please enter an integer from 0 to {}",
-        style("error:").bold().red(),
-        samples.len() - 1
+/// A run name, experiment name or flowcell can match several runs
+/// (e.g. a flowcell that was rescanned), so the user picks one.
+pub fn resolve_duplicate_runs(mut runs: Vec<&Run>) -> &Run {
+    eprintln!(
+        "{} Found {} runs matching the same name.",
+        style("warning:").bold().yellow(),
+        runs.len()
     );
 
-    let user_index = loop {
-        eprint!("Enter the sample index [0..{}]: ", samples.len() - 1);
-        let response: Result<usize, _> = try_read!();
-        break match response {
-            Ok(response) => {
-                if response > samples.len() - 1 {
-                    eprintln!("{}", invalid_input);
-                    continue;
-                }
-                response
-            }
-            Err(_) => {
-                eprintln!("{}", invalid_input);
-                continue;
+    let candidates: Vec<_> = runs
+        .iter()
+        .map(|run| (run.name.as_str(), run.date_created.as_str()))
+        .collect();
+    let user_index = prompt_index("run", &candidates);
+    runs.remove(user_index)
+}
+
+static MEGABYTE: u64 = 1024 * 1024;
+
+/// Part sizes commonly used by S3 upload tools
+static COMMON_PART_SIZES: [u64; 13] = [5, 8, 10, 15, 16, 25, 32, 50, 64, 100, 128, 256, 512];
+
+/// Upper bound on the part sizes tried when verifying a multipart etag
+static MAX_PART_SIZE_CANDIDATES: usize = 32;
+
+/// Number of part sizes hashed at once while reading through a file
+static PART_SIZES_PER_PASS: usize = 8;
+
+/// Running md5 digests of the parts of a multipart upload
+struct PartDigests {
+    part_size: u64,
+    filled: u64,
+    context: md5::Context,
+    digests: Vec<u8>,
+    parts: usize,
+}
+
+impl PartDigests {
+    fn new(part_size: u64) -> PartDigests {
+        PartDigests {
+            part_size,
+            filled: 0,
+            context: md5::Context::new(),
+            digests: Vec::new(),
+            parts: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let space = (self.part_size - self.filled).min(data.len() as u64) as usize;
+            self.context.consume(&data[..space]);
+            self.filled += space as u64;
+            data = &data[space..];
+            if self.filled == self.part_size {
+                self.finish_part();
             }
-        };
+        }
+    }
+
+    fn finish_part(&mut self) {
+        let context = std::mem::replace(&mut self.context, md5::Context::new());
+        self.digests.extend(&context.compute().0);
+        self.parts += 1;
+        self.filled = 0;
+    }
+
+    fn etag(mut self) -> String {
+        if self.filled > 0 || self.parts == 0 {
+            self.finish_part();
+        }
+        format!("{:x}-{}", md5::compute(&self.digests), self.parts)
+    }
+}
+
+/// Split an etag into its md5 digest and the number of
+/// parts (None for single part uploads).
+fn parse_etag(etag: &str) -> Result<(String, Option<u64>), failure::Error> {
+    let etag = etag.trim_matches('"').to_lowercase();
+    let (digest, parts) = match etag.find('-') {
+        Some(index) => (&etag[..index], Some(etag[index + 1..].parse::<u64>()?)),
+        None => (&etag[..], None),
     };
 
-    samples.remove(user_index)
+    if digest.len() != 32 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Unrecognized etag {}", etag);
+    }
+    Ok((etag.to_owned(), parts))
+}
+
+/// Part sizes that could have produced a multipart etag with
+/// `num_parts` parts for a file of `file_size` bytes, most likely first.
+///
+/// Every part except the last has the same size, so the part size
+/// lies between file_size / num_parts and (file_size - 1) / (num_parts - 1).
+/// Upload tools almost always use a multiple of a megabyte, either the
+/// smallest one that fits the part count, or a fixed size.
+pub fn etag_part_sizes(file_size: u64, num_parts: u64) -> Vec<u64> {
+    if num_parts == 0 || file_size == 0 {
+        return vec![];
+    }
+    if num_parts == 1 {
+        return vec![file_size];
+    }
+
+    let min = file_size.div_ceil(num_parts);
+    let max = (file_size - 1) / (num_parts - 1);
+    if min > max {
+        return vec![];
+    }
+
+    let round_up = |size: u64, multiple: u64| size.div_ceil(multiple) * multiple;
+    let multiples = |multiple: u64| (round_up(min, multiple)..=max).step_by(multiple as usize);
+
+    let mut candidates = vec![
+        min,
+        round_up(min, MEGABYTE),
+        round_up(min, 8 * MEGABYTE),
+        round_up(min, 5 * MEGABYTE),
+    ];
+    candidates.extend(COMMON_PART_SIZES.iter().map(|size| size * MEGABYTE));
+    candidates.extend(multiples(8 * MEGABYTE).take(MAX_PART_SIZE_CANDIDATES));
+    candidates.extend(multiples(5 * MEGABYTE).take(MAX_PART_SIZE_CANDIDATES));
+    candidates.extend(multiples(MEGABYTE).take(MAX_PART_SIZE_CANDIDATES));
+
+    let mut part_sizes = vec![];
+    for size in candidates {
+        if size >= min && size <= max && !part_sizes.contains(&size) {
+            part_sizes.push(size);
+        }
+    }
+    part_sizes.truncate(MAX_PART_SIZE_CANDIDATES);
+    part_sizes
 }
 
 /// Calculate s3 etag from known part size
+///
+/// Files that fit in a single part are uploaded
+/// without multipart, so their etag is a plain md5.
 pub fn s3_etag(
     mut rdr: impl Read,
     file_size: usize,
     part_size: usize,
 ) -> Result<String, failure::Error> {
-    if file_size <= part_size {
-        let mut buffer = vec![0; file_size];
-        rdr.read_exact(&mut buffer[..]).unwrap();
-        let digest = md5::compute(&buffer);
-        return Ok(format!("{:?}", digest));
+    if part_size == 0 {
+        bail!("Part size must be greater than 0.");
     }
 
-    let mut digests: Vec<u8> = Vec::new();
-    let mut parts = 0;
+    let mut buffer = vec![0; MEGABYTE as usize];
+    if file_size <= part_size {
+        let mut context = md5::Context::new();
+        loop {
+            let bcount = rdr.read(&mut buffer)?;
+            if bcount == 0 {
+                break;
+            }
+            context.consume(&buffer[..bcount]);
+        }
+        return Ok(format!("{:x}", context.compute()));
+    }
 
+    let mut part_digests = PartDigests::new(part_size as u64);
     loop {
-        let mut buffer = vec![0; part_size];
-        let bcount = rdr.read(&mut buffer[..]).unwrap();
+        let bcount = rdr.read(&mut buffer)?;
         if bcount == 0 {
             break;
         }
-        buffer.truncate(bcount);
-        let digest = md5::compute(&buffer);
-        digests.extend(&digest.0);
-        parts += 1;
-        if buffer.is_empty() {
-            break;
-        }
-    }
-
-    if digests.is_empty() || parts < 2 {
-        bail!("Could not calculate etag.");
-    } else {
-        Ok(format!("{:?}-{}", md5::compute(digests.as_slice()), parts))
+        part_digests.update(&buffer[..bcount]);
     }
+    Ok(part_digests.etag())
 }
 
-/// Calculate the s3 etag from path, and compare it with
+/// Calculate the s3 etag of the data in rdr, and compare it with
 /// the expected etag.
 ///
 /// The advantage of this function is that we don't need to know
-/// the etag part size (if we already know the file size and known etag).
-///
-/// TODO: part size calculation is not working. Do not use this function
+/// the etag part size. The candidates from `etag_part_sizes` are
+/// hashed several at a time while streaming through the data,
+/// rewinding for the next batch if none of them matched.
 pub fn verify_s3_etag(
-    mut rdr: impl Read,
+    mut rdr: impl Read + Seek,
     expected_etag: &str,
     file_size: u64,
 ) -> Result<bool, failure::Error> {
-    let num_parts = match expected_etag.find('-') {
-        Some(index) => expected_etag
-            .chars()
-            .skip(index + 1)
-            .collect::<String>()
-            .parse::<usize>()?,
+    let (expected_etag, num_parts) = parse_etag(expected_etag)?;
+    let mut buffer = vec![0; MEGABYTE as usize];
+
+    let num_parts = match num_parts {
+        Some(num_parts) => num_parts,
         None => {
-            let mut buffer = vec![0; file_size as usize];
-            rdr.read_exact(&mut buffer[..]).unwrap();
-            let digest = md5::compute(&buffer);
-            let actual_etag = format!("{:?}", digest);
-            return Ok(actual_etag == expected_etag);
+            let mut context = md5::Context::new();
+            loop {
+                let bcount = rdr.read(&mut buffer)?;
+                if bcount == 0 {
+                    break;
+                }
+                context.consume(&buffer[..bcount]);
+            }
+            return Ok(format!("{:x}", context.compute()) == expected_etag);
         }
     };
 
-    // Assumes AWS part sizes are a factor of one megabyte
-    static ONE_MEGABYTE: f64 = 1024.0 * 1024.0;
+    for part_sizes in etag_part_sizes(file_size, num_parts).chunks(PART_SIZES_PER_PASS) {
+        rdr.seek(SeekFrom::Start(0))?;
+        let mut candidates: Vec<_> = part_sizes
+            .iter()
+            .map(|&size| PartDigests::new(size))
+            .collect();
+        loop {
+            let bcount = rdr.read(&mut buffer)?;
+            if bcount == 0 {
+                break;
+            }
+            for candidate in candidates.iter_mut() {
+                candidate.update(&buffer[..bcount]);
+            }
+        }
 
-    // TODO: does not work 100% of the time.
-    let x = file_size as f64 / num_parts as f64;
-    let y = x % (ONE_MEGABYTE);
-    let part_size = (x - y + (ONE_MEGABYTE)) as usize;
+        for candidate in candidates {
+            let part_size = candidate.part_size;
+            if candidate.etag() == expected_etag {
+                info!(
+                    "Verified etag {} with part size {}",
+                    expected_etag, part_size
+                );
+                return Ok(true);
+            }
+        }
+    }
 
-    let mut digests: Vec<u8> = Vec::new();
-    let mut parts = 0;
+    Ok(false)
+}
 
-    loop {
-        let mut buffer = vec![0; part_size];
-        let bcount = rdr.read(&mut buffer[..]).unwrap();
-        if bcount == 0 {
-            break;
+/// Check whether a local file is a complete copy of a remote file.
+///
+/// The size has to match, and so does the etag, unless
+/// it is in a format we can't verify.
+pub fn matches_remote(path: &Path, file: &DataFile) -> Result<bool, failure::Error> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(false),
+    };
+    if !metadata.is_file() || metadata.len() != file.size as u64 {
+        return Ok(false);
+    }
+
+    match verify_s3_etag(File::open(path)?, &file.e_tag, file.size as u64) {
+        Ok(verified) => Ok(verified),
+        Err(e) => {
+            warn!("Could not verify etag of {}. {}", file.name, e);
+            Ok(true)
         }
-        buffer.truncate(bcount);
-        let digest = md5::compute(&buffer);
-        digests.extend(&digest.0);
-        parts += 1;
-        if buffer.is_empty() {
-            break;
+    }
+}
+
+/// How a local file compares to the project listing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    Ok,
+    Missing,
+    Extra,
+    WrongSize,
+    EtagMismatch,
+    /// The name would point outside the directory, so it wasn't checked
+    UnsafeName,
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self {
+            FileStatus::Ok => "ok",
+            FileStatus::Missing => "missing",
+            FileStatus::Extra => "extra",
+            FileStatus::WrongSize => "wrong size",
+            FileStatus::EtagMismatch => "etag mismatch",
+            FileStatus::UnsafeName => "unsafe name",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/// Compare every remote file with its copy in `dir`, and list local
+/// files that aren't part of the listing. Nothing is downloaded.
+///
+/// Files are looked for at their destination, so folders made by a
+/// layout (or run files kept in their folders) are searched as well.
+pub fn verify_directory(
+    files: &[DataFile],
+    dir: &Path,
+) -> Result<Vec<(FileStatus, String)>, failure::Error> {
+    let mut statuses: Vec<(FileStatus, String)> = files
+        .par_iter()
+        .map(|file| {
+            let destination = file.destination();
+            let shown = destination.display().to_string();
+            if check_destination(destination).is_err() {
+                return Ok((FileStatus::UnsafeName, shown));
+            }
+            let path = dir.join(destination);
+            let status = match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() => {
+                    if metadata.len() != file.size as u64 {
+                        FileStatus::WrongSize
+                    } else {
+                        match verify_s3_etag(File::open(&path)?, &file.e_tag, file.size as u64) {
+                            Ok(false) => FileStatus::EtagMismatch,
+                            Ok(true) => FileStatus::Ok,
+                            Err(e) => {
+                                warn!("Could not verify etag of {}. {}", file.name, e);
+                                FileStatus::Ok
+                            }
+                        }
+                    }
+                }
+                _ => FileStatus::Missing,
+            };
+            Ok((status, shown))
+        })
+        .collect::<Result<_, failure::Error>>()?;
+
+    let remote: HashSet<&Path> = files.iter().map(|file| file.destination()).collect();
+    for path in local_files(dir)? {
+        if !remote.contains(path.as_path()) {
+            statuses.push((FileStatus::Extra, path.display().to_string()));
         }
     }
 
-    let actual_etag = if digests.is_empty() || parts < 2 {
-        bail!("Could not calculate etag.");
-    } else {
-        format!("{:?}-{}", md5::compute(digests.as_slice()), parts)
-    };
+    Ok(statuses)
+}
+
+/// Every file in `dir` and its subfolders, relative to `dir`
+fn local_files(dir: &Path) -> Result<Vec<PathBuf>, failure::Error> {
+    let mut files = vec![];
+    let mut folders = vec![PathBuf::new()];
+    while let Some(folder) = folders.pop() {
+        for entry in fs::read_dir(dir.join(&folder))? {
+            let entry = entry?;
+            let path = folder.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                folders.push(path);
+            } else if file_type.is_file() {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// File names come from the server, and end up in paths under the output
+/// directory. Anything that could lead out of it (a `..`, an absolute path,
+/// a drive prefix) is refused, rather than guessing what was meant.
+pub fn check_destination(path: &Path) -> Result<(), failure::Error> {
+    let mut has_name = false;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => has_name = true,
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => bail!(
+                "{} would be saved outside the output directory",
+                path.display()
+            ),
+        }
+    }
+    if !has_name {
+        bail!("\"{}\" is not a valid file name", path.display());
+    }
+    Ok(())
+}
+
+/// Move a file out of the way, to the first free name of
+/// e.g. sample.bam.1, sample.bam.2 and so on
+pub fn move_aside(path: &Path) -> Result<PathBuf, failure::Error> {
+    let name = path.file_name().unwrap_or_default().to_os_string();
+    for n in 1.. {
+        let mut moved = name.clone();
+        moved.push(format!(".{}", n));
+        let moved = path.with_file_name(moved);
+        if fs::symlink_metadata(&moved).is_err() {
+            fs::rename(path, &moved).with_context(|e| {
+                format!("Could not move {} out of the way. {}", path.display(), e)
+            })?;
+            return Ok(moved);
+        }
+    }
+    unreachable!()
+}
 
-    Ok(actual_etag == expected_etag)
+/// In-progress downloads are written next to their final
+/// destination, e.g. sample_R1.fastq.gz.part
+pub fn part_path(output: &Path) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    output.with_file_name(name)
+}
+
+/// Where a complete download whose etag didn't match is moved, e.g.
+/// sample.bam.etag-mismatch, so a rerun doesn't take it as finished
+pub fn mismatch_path(output: &Path) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(".etag-mismatch");
+    output.with_file_name(name)
+}
+
+/// Large files downloaded in segments are preallocated to their full
+/// size, so they can't share the .part name, whose size is how much has
+/// been received. They use e.g. sample.bam.segments.part, and how far
+/// each segment got is logged in sample.bam.segments
+pub fn segments_paths(output: &Path) -> (PathBuf, PathBuf) {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(".segments");
+    let done = output.with_file_name(&name);
+    name.push(".part");
+    (output.with_file_name(name), done)
+}
+
+/// How many bytes of each segment were written, by the offset the
+/// segment starts at, from the `start received` lines of a segments file.
+/// Lines are appended as data is written, so the largest count wins.
+pub fn segments_received(text: &str) -> HashMap<u64, u64> {
+    let mut received = HashMap::new();
+    for line in text.lines() {
+        let mut fields = line.split(' ').map(|field| field.parse::<u64>());
+        if let (Some(Ok(start)), Some(Ok(bytes)), None) =
+            (fields.next(), fields.next(), fields.next())
+        {
+            let entry = received.entry(start).or_insert(0);
+            *entry = bytes.max(*entry);
+        }
+    }
+    received
+}
+
+/// Make a rename into `dir` survive a crash. Only unix can open a
+/// directory to sync it; elsewhere this does nothing.
+pub fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        File::open(dir)?.sync_all()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+        Ok(())
+    }
+}
+
+/// Bytes of a download that an earlier run left on disk, in
+/// either kind of part file. A preallocated segments file takes
+/// up its full size however much of it has been downloaded.
+pub fn bytes_present(output: &Path) -> u64 {
+    let (segments_part, _) = segments_paths(output);
+    [part_path(output), segments_part]
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Write all of `buf` at `offset`, without using the file's cursor,
+/// so several tasks can write to the same file at once
+pub fn write_all_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+        file.write_all_at(buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+        let mut written = 0;
+        while written < buf.len() {
+            match file.seek_write(&buf[written..], offset + written as u64) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Convert bytes to human readable form.
@@ -1,14 +1,101 @@
-use crate::MultiApi;
-use failure::bail;
+use crate::cache::{Cache, CacheSettings};
+use crate::http::{HttpSettings, RetryPolicy};
+use crate::rate::{self, RateLimiter};
+use crate::{Account, MultiApi};
+use failure::{bail, ResultExt};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::{self, File};
+use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// An account as written in the config file. Either the short form
+///
+/// ```toml
+/// 11111111 = "token"
+/// ```
+///
+/// or a table, which allows pointing the account at a different API url
+/// and capping the bandwidth used by its downloads
+///
+/// ```toml
+/// [22222222]
+/// token = "token"
+/// url = "https://api.euc1.sh.basespace.illumina.com/v1pre3"
+/// limit_rate = "20M"
+/// ```
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum AccountEntry {
+    Token(String),
+    Table {
+        token: String,
+        url: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        limit_rate: Option<String>,
+    },
+}
+
+impl AccountEntry {
+    fn into_account(self) -> Result<Account, failure::Error> {
+        match self {
+            AccountEntry::Token(token) => Ok(Account::new(&token, None)),
+            AccountEntry::Table {
+                token,
+                url,
+                limit_rate,
+            } => {
+                let mut account = Account::new(&token, url.as_deref());
+                if let Some(limit_rate) = limit_rate {
+                    let limiter = RateLimiter::new(rate::parse_rate(&limit_rate)?);
+                    account.rate_limit = Some(Arc::new(limiter));
+                }
+                Ok(account)
+            }
+        }
+    }
+}
+
+/// Top-level tables that hold settings, so can't be user IDs
+static SETTINGS_TABLES: &[&str] = &["cache", "http"];
+
+/// The whole config file. Every top-level key that isn't one of
+/// the settings tables is an account.
+#[derive(Deserialize, Debug)]
+struct Config {
+    #[serde(default)]
+    cache: CacheSettings,
+    #[serde(default)]
+    http: HttpSettings,
+    #[serde(flatten)]
+    accounts: HashMap<String, AccountEntry>,
+}
 
 pub struct Workspace {
     pub config_file: PathBuf,
 }
 
+/// Create (or truncate) a file with 0600 permissions, since
+/// config files hold access tokens.
+fn create_private(path: &Path) -> Result<File, failure::Error> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options.open(path)?;
+        // mode only applies to new files
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        Ok(file)
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(options.open(path)?)
+    }
+}
+
 impl Workspace {
     /// Use the config file stored in
     /// $HOME/.config/basespace-dl/default.toml
@@ -21,7 +108,7 @@ impl Workspace {
 
         let config_file = config_dir.join("default.toml");
         if !config_file.exists() {
-            File::create(&config_file)?;
+            create_private(&config_file)?;
         }
 
         Ok(Workspace { config_file })
@@ -41,7 +128,7 @@ impl Workspace {
             fs::create_dir_all(&config_dir)?;
         }
 
-        let config_file = config_dir.join(&format!("{}.toml", name));
+        let config_file = config_dir.join(format!("{}.toml", name));
         if !config_file.exists() {
             bail!("{} does not exist.", config_file.to_str().unwrap())
         }
@@ -49,28 +136,91 @@ impl Workspace {
         Ok(Workspace { config_file })
     }
 
-    fn accounts(&self) -> Result<HashMap<String, String>, failure::Error> {
+    fn read_config(&self) -> Result<String, failure::Error> {
         let mut file = File::open(&self.config_file)?;
         let mut buffer = String::new();
         file.read_to_string(&mut buffer)?;
-        let accounts: HashMap<String, String> = toml::from_str(&buffer)?;
-        Ok(accounts)
+        Ok(buffer)
     }
 
-    pub fn add_account(&self, user_id: &str, token: &str) -> Result<(), failure::Error> {
-        let mut accounts = self.accounts()?;
-        let inserted = accounts.insert(user_id.to_owned(), token.to_owned());
-        if inserted.is_some() {
-            bail!("User {} is already in config file.", user_id);
-        }
-        let buffer = toml::to_string(&accounts)?;
-        let mut file = File::create(&self.config_file)?;
+    /// Write the config back, keeping it readable
+    /// and writeable by only the owner.
+    fn write_config(&self, config: toml::value::Table) -> Result<(), failure::Error> {
+        let buffer = toml::to_string(&toml::Value::Table(config))?;
+        let mut file = create_private(&self.config_file)?;
         write!(&mut file, "{}", buffer)?;
         Ok(())
     }
 
+    fn config(&self) -> Result<Config, failure::Error> {
+        Ok(toml::from_str(&self.read_config()?)?)
+    }
+
+    pub fn accounts(&self) -> Result<HashMap<String, Account>, failure::Error> {
+        Self::to_accounts(self.config()?.accounts)
+    }
+
+    fn to_accounts(
+        entries: HashMap<String, AccountEntry>,
+    ) -> Result<HashMap<String, Account>, failure::Error> {
+        entries
+            .into_iter()
+            .map(|(user_id, entry)| {
+                let account = entry
+                    .into_account()
+                    .with_context(|e| format!("Account {}: {}", user_id, e))?;
+                Ok((user_id, account))
+            })
+            .collect()
+    }
+
+    /// Add an account to the config file. If no url is given,
+    /// the short `user_id = "token"` form is written.
+    pub fn add_account(
+        &self,
+        user_id: &str,
+        token: &str,
+        url: Option<&str>,
+    ) -> Result<(), failure::Error> {
+        // Edit the raw toml so the other entries are written back untouched
+        let mut config: toml::value::Table = toml::from_str(&self.read_config()?)?;
+        if SETTINGS_TABLES.contains(&user_id) || config.contains_key(user_id) {
+            bail!("User {} is already in config file.", user_id);
+        }
+
+        let entry = match url {
+            Some(url) => AccountEntry::Table {
+                token: token.to_owned(),
+                url: Some(url.to_owned()),
+                limit_rate: None,
+            },
+            None => AccountEntry::Token(token.to_owned()),
+        };
+        config.insert(user_id.to_owned(), toml::Value::try_from(entry)?);
+        self.write_config(config)
+    }
+
+    pub fn remove_account(&self, user_id: &str) -> Result<(), failure::Error> {
+        let mut config: toml::value::Table = toml::from_str(&self.read_config()?)?;
+        if SETTINGS_TABLES.contains(&user_id) || config.remove(user_id).is_none() {
+            bail!("User {} is not in config file.", user_id);
+        }
+        self.write_config(config)
+    }
+
+    /// The response cache, as configured by the [cache] table
+    pub fn cache(&self) -> Result<Cache, failure::Error> {
+        Ok(Cache::new(&self.config()?.cache))
+    }
+
+    /// The [http] settings, for requests made without a MultiApi
+    pub fn http_settings(&self) -> Result<HttpSettings, failure::Error> {
+        Ok(self.config()?.http)
+    }
+
     pub fn to_multiapi(&self) -> Result<MultiApi, failure::Error> {
-        let accounts = self.accounts()?;
+        let config = self.config()?;
+        let accounts = Self::to_accounts(config.accounts)?;
 
         if accounts.keys().len() == 0 {
             bail!(
@@ -79,6 +229,11 @@ impl Workspace {
             );
         }
 
-        Ok(MultiApi::new(self.accounts()?))
+        let mut multi = MultiApi::new(accounts);
+        multi.cache = Some(Cache::new(&config.cache));
+        multi.retry = RetryPolicy::new(config.http.retries);
+        multi.client = config.http.client()?;
+        multi.http = config.http;
+        Ok(multi)
     }
 }
@@ -1,15 +1,598 @@
-// use basespace_dl::util::s3_etag;
+use basespace_dl::api::{DataFile, FileResponse, Paginated, Run, Sample, User};
+use basespace_dl::cache::Cache;
+use basespace_dl::http::{Failed, RetryPolicy};
+use basespace_dl::layout::{resolve_collisions, Collisions, Layout, Origin};
+use basespace_dl::rate::{parse_rate, RateLimiter};
+use basespace_dl::util::{
+    bytes_present, check_destination, etag_part_sizes, move_aside, part_path, s3_etag,
+    segments_paths, segments_received, verify_directory, verify_s3_etag, write_all_at, FileStatus,
+};
+use basespace_dl::workspace::Workspace;
+use failure::format_err;
+use std::fs::{self, OpenOptions};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-// #[test]
-// fn test_verify_s3_etag() {
+// 26 MiB of the same byte, so it spans several parts for every
+// part size below. Expected etags were calculated independently.
+const FILE_SIZE: usize = 27_262_976;
+const MEGABYTE: usize = 1024 * 1024;
 
-//     // I have uploaded this file to amazon to get
-//     // the expected etag
-//     let part_size = 1024 * 1024 * 25;
-//     let file_size = 27_262_976;
-//     let artifical_file = vec![b'b'; file_size];
-//     let expected_etag = "f6df70255a555bf8c077e5c95f97c1a0-2";
+fn artificial_file() -> Vec<u8> {
+    vec![b'b'; FILE_SIZE]
+}
 
-//     let actual_etag = s3_etag(artifical_file.as_slice(), file_size, part_size).unwrap();
-//     assert_eq!(expected_etag, &actual_etag)
-// }
+#[test]
+fn test_s3_etag() {
+    let file = artificial_file();
+    let etag = s3_etag(file.as_slice(), FILE_SIZE, 25 * MEGABYTE).unwrap();
+    assert_eq!("487ec5782b9100812a4d97aebfc0957e-2", &etag);
+
+    let etag = s3_etag(file.as_slice(), FILE_SIZE, 8 * MEGABYTE).unwrap();
+    assert_eq!("8f217be7fad2a141c6fc5dcedbd3f0f0-4", &etag);
+
+    // Single part uploads are a plain md5
+    let etag = s3_etag(file.as_slice(), FILE_SIZE, 32 * MEGABYTE).unwrap();
+    assert_eq!("3bbb7e60e8db04ddc3b1bf20a08666e3", &etag);
+}
+
+#[test]
+fn test_verify_s3_etag() {
+    let file = artificial_file();
+    let size = FILE_SIZE as u64;
+    let expected = [
+        "487ec5782b9100812a4d97aebfc0957e-2",
+        "8f217be7fad2a141c6fc5dcedbd3f0f0-4",
+        "e9cfc0c090f61da5263802760415d06f-6",
+        "\"3bbb7e60e8db04ddc3b1bf20a08666e3\"",
+    ];
+    for etag in &expected {
+        assert!(
+            verify_s3_etag(Cursor::new(&file), etag, size).unwrap(),
+            "{}",
+            etag
+        );
+    }
+
+    let mut corrupted = file.clone();
+    corrupted[MEGABYTE] = b'c';
+    assert!(!verify_s3_etag(Cursor::new(&corrupted), expected[1], size).unwrap());
+    assert!(!verify_s3_etag(Cursor::new(&corrupted), expected[3], size).unwrap());
+
+    assert!(verify_s3_etag(Cursor::new(&file), "not-an-etag", size).is_err());
+}
+
+#[test]
+fn test_etag_part_sizes() {
+    let mb = MEGABYTE as u64;
+    let part_sizes = etag_part_sizes(FILE_SIZE as u64, 2);
+    assert_eq!(part_sizes[0], 13 * mb);
+    assert!(part_sizes.contains(&(25 * mb)));
+    assert!(part_sizes
+        .iter()
+        .all(|&size| size >= 13 * mb && size < 26 * mb));
+
+    // Every part but the last is full, so 4 parts of 26 MiB can't be 9 MiB each
+    assert!(!etag_part_sizes(FILE_SIZE as u64, 4).contains(&(9 * mb)));
+    assert!(etag_part_sizes(10, 6).is_empty());
+}
+
+#[test]
+fn test_retry_backoff() {
+    let policy = RetryPolicy::new(10);
+    for retry in 1..=10 {
+        let full = (policy.base_delay * 2u32.pow(retry - 1)).min(policy.max_delay);
+        let delay = policy.backoff(retry);
+        assert!(
+            delay >= full / 2 && delay <= full,
+            "retry {}: {:?}",
+            retry,
+            delay
+        );
+    }
+    // Doubling must not overflow, however many retries
+    assert!(policy.backoff(u32::MAX) <= policy.max_delay);
+    assert!(policy.backoff(1) <= Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn test_retry_gives_up() {
+    let policy = RetryPolicy {
+        retries: 1,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(10),
+    };
+    let start = Instant::now();
+    let result: Result<(), _> = policy
+        .run("sample.fastq.gz", || async {
+            Err(Failed {
+                error: format_err!("slow down"),
+                retryable: true,
+                retry_after: Some(Duration::from_secs(3600)),
+            })
+        })
+        .await;
+    // Retry-After is capped at the max delay
+    assert!(start.elapsed() < Duration::from_secs(10));
+    let error = result.unwrap_err();
+    assert_eq!(error.to_string(), "sample.fastq.gz: slow down");
+}
+
+fn file_page(items: usize, total_count: Option<usize>) -> FileResponse {
+    let items: Vec<String> = (0..items)
+        .map(|i| {
+            format!(
+                r#"{{"Id": "{}", "Name": "{}.fastq.gz", "Size": 1, "ETag": "a"}}"#,
+                i, i
+            )
+        })
+        .collect();
+    let total_count = match total_count {
+        Some(total_count) => format!(r#", "TotalCount": {}"#, total_count),
+        None => String::new(),
+    };
+    let body = format!(
+        r#"{{"Response": {{"Items": [{}]{}}}}}"#,
+        items.join(", "),
+        total_count
+    );
+    serde_json::from_str(&body).unwrap()
+}
+
+#[test]
+fn test_last_page() {
+    // The server's TotalCount decides, even if it gave fewer items than asked for
+    assert!(!file_page(2, Some(5)).is_last(2, 4));
+    assert!(file_page(2, Some(5)).is_last(5, 4));
+    assert!(!file_page(4, Some(9)).is_last(4, 4));
+
+    // Without one, only a short page ends the listing
+    assert!(!file_page(4, None).is_last(4, 4));
+    assert!(file_page(3, None).is_last(7, 4));
+
+    // An empty page always does, so a wrong TotalCount can't loop forever
+    assert!(file_page(0, Some(100)).is_last(8, 4));
+    assert!(file_page(0, None).is_last(0, 4));
+}
+
+fn run(name: &str, flowcell_barcode: Option<&str>) -> Run {
+    Run {
+        name: name.to_owned(),
+        id: String::from("r1"),
+        experiment_name: Some(String::from("project17890")),
+        flowcell_barcode: flowcell_barcode.map(|barcode| barcode.to_owned()),
+        user_owned_by: User {
+            name: String::from("Lab"),
+            id: String::from("1001"),
+        },
+        user_fetched_by_id: String::from("1001"),
+        date_created: String::from("2022-01-01T00:00:00.0000000"),
+    }
+}
+
+#[test]
+fn test_run_flowcell_id() {
+    let name = "220101_M00001_0001_000000000-ABCDE";
+
+    // The barcode from the API wins over the name
+    assert_eq!(
+        Some("000000000-FGHIJ"),
+        run(name, Some("000000000-FGHIJ")).flowcell_id()
+    );
+    assert_eq!(Some("000000000-ABCDE"), run(name, None).flowcell_id());
+    assert_eq!(None, run("resequenced_run", None).flowcell_id());
+}
+
+#[test]
+fn test_run_matches() {
+    let run = run("220101_M00001_0001_000000000-ABCDE", None);
+    assert!(run.matches("220101_M00001_0001_000000000-ABCDE"));
+    assert!(run.matches("project17890"));
+    assert!(run.matches("000000000-ABCDE"));
+    assert!(!run.matches("ABCDE"));
+    assert!(!run.matches("220101"));
+}
+
+#[test]
+fn test_segments_received() {
+    let text = "0 1048576\n67108864 1048576\n0 2097152\n0\ngarbage 1\n67108864 5 6\n";
+    let received = segments_received(text);
+    assert_eq!(2, received.len());
+    assert_eq!(Some(&2_097_152), received.get(&0));
+    assert_eq!(Some(&1_048_576), received.get(&67_108_864));
+
+    // A line cut short by a crash doesn't lose what came before
+    let received = segments_received("0 2097152\n0 10");
+    assert_eq!(Some(&2_097_152), received.get(&0));
+    assert!(segments_received("").is_empty());
+}
+
+#[test]
+fn test_segmented_write() {
+    let (part, done) = segments_paths(Path::new("out/sample.bam"));
+    assert_eq!(Path::new("out/sample.bam.segments.part"), part);
+    assert_eq!(Path::new("out/sample.bam.segments"), done);
+
+    // Segments finish in any order, but must end up in place
+    let path = std::env::temp_dir().join("basespace-dl-test-segmented-write");
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+    file.set_len(12).unwrap();
+    write_all_at(&file, b"world!", 6).unwrap();
+    write_all_at(&file, b"hello ", 0).unwrap();
+    assert_eq!(b"hello world!", fs::read(&path).unwrap().as_slice());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_parse_rate() {
+    assert_eq!(500, parse_rate("500").unwrap());
+    assert_eq!(500 * 1024, parse_rate("500K").unwrap());
+    assert_eq!(50 * 1024 * 1024, parse_rate("50M").unwrap());
+    assert_eq!(1536 * 1024 * 1024, parse_rate("1.5g").unwrap());
+    assert!(parse_rate("50X").is_err());
+    assert!(parse_rate("M").is_err());
+    assert!(parse_rate("0").is_err());
+    assert!(parse_rate("-5M").is_err());
+}
+
+#[tokio::test]
+async fn test_rate_limiter() {
+    // The first quarter second of traffic goes straight through,
+    // anything more has to wait for the bucket to refill
+    let limiter = RateLimiter::new(1000);
+    let start = Instant::now();
+    limiter.acquire(250).await;
+    assert!(start.elapsed() < Duration::from_millis(100));
+    limiter.acquire(250).await;
+    limiter.acquire(250).await;
+    assert!(start.elapsed() >= Duration::from_millis(450));
+}
+
+#[test]
+fn test_bytes_present() {
+    let dir = std::env::temp_dir().join("basespace-dl-test-bytes-present");
+    fs::create_dir_all(&dir).unwrap();
+    let output = dir.join("sample.bam");
+    assert_eq!(0, bytes_present(&output));
+
+    fs::write(part_path(&output), vec![0; 100]).unwrap();
+    assert_eq!(100, bytes_present(&output));
+
+    let (part, _) = segments_paths(&output);
+    fs::write(&part, vec![0; 300]).unwrap();
+    assert_eq!(300, bytes_present(&output));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn data_file(name: &str, sample: Option<Sample>) -> DataFile {
+    DataFile {
+        id: String::from("f1"),
+        name: name.to_owned(),
+        size: 10,
+        e_tag: String::from("d41d8cd98f00b204e9800998ecf8427e"),
+        path: None,
+        sample,
+        destination: None,
+    }
+}
+
+fn sample(id: &str) -> Sample {
+    Sample {
+        id: id.to_owned(),
+        status: String::from("Complete"),
+        name: String::from("A01"),
+        experiment_name: None,
+        date_created: String::from("2020-01-01T00:00:00.0000000"),
+    }
+}
+
+#[test]
+fn test_layout() {
+    let origin = Origin {
+        name: "project/17890",
+        id: "p1",
+        owner: "Lab",
+    };
+    let file = data_file("A01_R1.fastq.gz", Some(sample("s1")));
+
+    let layout = Layout::parse("{project}/{sample}/{file}").unwrap();
+    assert_eq!(
+        Path::new("project_17890/A01/A01_R1.fastq.gz"),
+        layout.render(&origin, &file).unwrap()
+    );
+    let layout = Layout::parse("/{project_owner}//{sample_id}-{file_id}-{file}").unwrap();
+    assert_eq!(
+        Path::new("Lab/s1-f1-A01_R1.fastq.gz"),
+        layout.render(&origin, &file).unwrap()
+    );
+
+    // Run files keep their folders, but have no sample
+    let run_file = data_file("InterOp/QMetricsOut.bin", None);
+    let layout = Layout::parse("{project}/{file}").unwrap();
+    assert_eq!(
+        Path::new("project_17890/InterOp/QMetricsOut.bin"),
+        layout.render(&origin, &run_file).unwrap()
+    );
+    let layout = Layout::parse("{sample}/{file}").unwrap();
+    assert!(layout.render(&origin, &run_file).is_err());
+    let layout = Layout::parse("{experiment_name}/{file}").unwrap();
+    assert!(layout.render(&origin, &file).is_err());
+
+    assert!(Layout::parse("{project}/{unknown}/{file}").is_err());
+    assert!(Layout::parse("{project}/{file").is_err());
+    assert!(Layout::parse("{project}/{sample}").is_err());
+}
+
+#[test]
+fn test_check_destination() {
+    for name in &[
+        "S1_R1.fastq.gz",
+        "InterOp/QMetricsOut.bin",
+        "./S1_R1.fastq.gz",
+        "...",
+        "a..b.txt",
+        "..hidden",
+    ] {
+        assert!(check_destination(Path::new(name)).is_ok(), "{}", name);
+    }
+    for name in &[
+        "../evil.txt",
+        "..",
+        "InterOp/../../evil.txt",
+        "a/b/../../../evil.txt",
+        "/etc/passwd",
+        "/",
+        "",
+        ".",
+    ] {
+        assert!(check_destination(Path::new(name)).is_err(), "{}", name);
+    }
+    #[cfg(windows)]
+    for name in &["C:\\evil.txt", "C:evil.txt", "\\\\server\\share\\evil.txt"] {
+        assert!(check_destination(Path::new(name)).is_err(), "{}", name);
+    }
+
+    // Values in a layout can't add a slash, but can still be ".."
+    let origin = Origin {
+        name: "..",
+        id: "p1",
+        owner: "Lab",
+    };
+    let layout = Layout::parse("{project}/{file}").unwrap();
+    let path = layout
+        .render(&origin, &data_file("evil.txt", None))
+        .unwrap();
+    assert!(check_destination(&path).is_err());
+    let origin = Origin {
+        name: "../..",
+        ..origin
+    };
+    let path = layout
+        .render(&origin, &data_file("evil.txt", None))
+        .unwrap();
+    assert!(check_destination(&path).is_ok());
+}
+
+#[test]
+fn test_resolve_collisions() {
+    let files = vec![
+        data_file("A01_R1.fastq.gz", Some(sample("s1"))),
+        data_file("A01_R1.fastq.gz", Some(sample("s2"))),
+        data_file("A01_R2.fastq.gz", Some(sample("s1"))),
+    ];
+    let destinations = |files: &[DataFile]| -> Vec<PathBuf> {
+        files
+            .iter()
+            .map(|file| file.destination().to_owned())
+            .collect()
+    };
+
+    let mut unchanged = files.clone();
+    assert!(resolve_collisions(&mut unchanged, Collisions::Error).is_err());
+    let mut unique = vec![files[0].clone(), files[2].clone()];
+    resolve_collisions(&mut unique, Collisions::Error).unwrap();
+    assert_eq!(
+        vec![Path::new("A01_R1.fastq.gz"), Path::new("A01_R2.fastq.gz")],
+        destinations(&unique)
+    );
+
+    // Only the files that collide are moved, and all of them
+    let mut suffixed = files.clone();
+    resolve_collisions(&mut suffixed, Collisions::Suffix).unwrap();
+    assert_eq!(
+        vec![
+            Path::new("A01_R1_s1.fastq.gz"),
+            Path::new("A01_R1_s2.fastq.gz"),
+            Path::new("A01_R2.fastq.gz")
+        ],
+        destinations(&suffixed)
+    );
+
+    let mut nested = files.clone();
+    nested[0].destination = Some(PathBuf::from("project/A01_R1.fastq.gz"));
+    nested[1].destination = Some(PathBuf::from("project/A01_R1.fastq.gz"));
+    resolve_collisions(&mut nested, Collisions::Nest).unwrap();
+    assert_eq!(
+        vec![
+            Path::new("project/s1/A01_R1.fastq.gz"),
+            Path::new("project/s2/A01_R1.fastq.gz"),
+            Path::new("A01_R2.fastq.gz")
+        ],
+        destinations(&nested)
+    );
+
+    // Nothing tells apart files without a sample
+    let mut run_files = vec![data_file("a.bin", None), data_file("a.bin", None)];
+    assert!(resolve_collisions(&mut run_files, Collisions::Suffix).is_err());
+}
+
+#[test]
+fn test_move_aside() {
+    let dir = std::env::temp_dir().join("basespace-dl-test-move-aside");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("sample.bam");
+    fs::write(&path, "first").unwrap();
+    assert_eq!(dir.join("sample.bam.1"), move_aside(&path).unwrap());
+    fs::write(&path, "second").unwrap();
+    assert_eq!(dir.join("sample.bam.2"), move_aside(&path).unwrap());
+    assert!(!path.exists());
+    assert_eq!(
+        "first",
+        fs::read_to_string(dir.join("sample.bam.1")).unwrap()
+    );
+    assert_eq!(
+        "second",
+        fs::read_to_string(dir.join("sample.bam.2")).unwrap()
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_verify_directory() {
+    let dir = std::env::temp_dir().join("basespace-dl-test-verify-directory");
+    fs::create_dir_all(dir.join("A01")).unwrap();
+    fs::write(dir.join("A01/ok.fastq.gz"), "0123456789").unwrap();
+    fs::write(dir.join("A01/changed.fastq.gz"), "9876543210").unwrap();
+    fs::write(dir.join("A01/notes.txt"), "").unwrap();
+
+    let mut files: Vec<DataFile> = ["ok.fastq.gz", "changed.fastq.gz", "missing.fastq.gz"]
+        .iter()
+        .map(|name| {
+            let mut file = data_file(name, None);
+            file.e_tag = String::from("781e5e245d69b566979b86e28d23f2c7");
+            file.destination = Some(Path::new("A01").join(name));
+            file
+        })
+        .collect();
+    files.push(data_file("../evil.txt", None));
+
+    let mut statuses = verify_directory(&files, &dir).unwrap();
+    statuses.sort_by(|a, b| a.1.cmp(&b.1));
+    let expected = [
+        (FileStatus::UnsafeName, "../evil.txt"),
+        (FileStatus::EtagMismatch, "A01/changed.fastq.gz"),
+        (FileStatus::Missing, "A01/missing.fastq.gz"),
+        (FileStatus::Extra, "A01/notes.txt"),
+        (FileStatus::Ok, "A01/ok.fastq.gz"),
+    ];
+    let statuses: Vec<(FileStatus, &str)> = statuses
+        .iter()
+        .map(|(status, name)| (*status, name.as_str()))
+        .collect();
+    assert_eq!(expected.to_vec(), statuses);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_workspace_accounts() {
+    let dir = std::env::temp_dir().join("basespace-dl-test-workspace");
+    fs::create_dir_all(&dir).unwrap();
+    let config_file = dir.join("test.toml");
+    fs::write(&config_file, "1001 = \"token1\"\n\n[http]\nretries = 2\n").unwrap();
+    let ws = Workspace { config_file };
+
+    ws.add_account("2002", "token2", None).unwrap();
+    ws.add_account("3003", "token3", Some("http://localhost/v1pre3"))
+        .unwrap();
+    assert!(ws.add_account("2002", "token2", None).is_err());
+    assert!(ws.add_account("http", "token", None).is_err());
+
+    let accounts = ws.accounts().unwrap();
+    let mut ids: Vec<&str> = accounts.keys().map(|id| id.as_str()).collect();
+    ids.sort_unstable();
+    assert_eq!(vec!["1001", "2002", "3003"], ids);
+    assert_eq!("http://localhost/v1pre3", accounts["3003"].url);
+    assert_eq!(2, ws.http_settings().unwrap().retries);
+
+    // Settings tables aren't accounts, whether or not they're there
+    assert!(ws.remove_account("http").is_err());
+    assert!(ws.remove_account("cache").is_err());
+    assert!(ws.remove_account("4004").is_err());
+    ws.remove_account("2002").unwrap();
+    assert!(!ws.accounts().unwrap().contains_key("2002"));
+    assert_eq!(2, ws.http_settings().unwrap().retries);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&ws.config_file).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+const PROJECTS_URL: &str = "http://localhost/v1pre3/users/current/projects?offset=0&limit=1024";
+
+fn test_cache(name: &str, ttl: u64) -> Cache {
+    let dir = std::env::temp_dir().join(name);
+    let _ = fs::remove_dir_all(&dir);
+    Cache {
+        dir,
+        ttl: Duration::from_secs(ttl),
+        refresh: false,
+    }
+}
+
+#[test]
+fn test_cache_ttl() {
+    let mut cache = test_cache("basespace-dl-test-cache-ttl", 1);
+    assert_eq!(None, cache.get("1001", PROJECTS_URL));
+    cache.put("1001", PROJECTS_URL, "first");
+    assert_eq!(Some("first"), cache.get("1001", PROJECTS_URL).as_deref());
+    assert_eq!(None, cache.get("2002", PROJECTS_URL));
+
+    // --refresh skips what is cached, but still stores the fresh response
+    cache.refresh = true;
+    assert_eq!(None, cache.get("1001", PROJECTS_URL));
+    cache.put("1001", PROJECTS_URL, "second");
+    cache.refresh = false;
+    assert_eq!(Some("second"), cache.get("1001", PROJECTS_URL).as_deref());
+
+    // Expired responses are only used offline
+    std::thread::sleep(Duration::from_millis(1100));
+    assert_eq!(None, cache.get("1001", PROJECTS_URL));
+    assert_eq!(
+        Some("second"),
+        cache.get_stale("1001", PROJECTS_URL).as_deref()
+    );
+    fs::remove_dir_all(&cache.dir).unwrap();
+
+    // A ttl of 0 turns the cache off
+    let cache = test_cache("basespace-dl-test-cache-off", 0);
+    cache.put("1001", PROJECTS_URL, "first");
+    assert!(!cache.dir.exists());
+    assert_eq!(None, cache.get("1001", PROJECTS_URL));
+}
+
+#[test]
+fn test_cache_clear() {
+    let cache = test_cache("basespace-dl-test-cache-clear", 600);
+    cache.clear().unwrap();
+
+    cache.put("1001", PROJECTS_URL, "first");
+    cache.put("2002", PROJECTS_URL, "first");
+    // The directory can be configured to be one that is also used for other things
+    let dir = &cache.dir;
+    fs::write(dir.join("notes.txt"), "").unwrap();
+    fs::write(dir.join("2002/keep.json"), "").unwrap();
+    fs::create_dir_all(dir.join("results")).unwrap();
+    fs::write(dir.join("results/summary.json"), "").unwrap();
+    fs::create_dir_all(dir.join("empty")).unwrap();
+
+    cache.clear().unwrap();
+    assert_eq!(None, cache.get_stale("1001", PROJECTS_URL));
+    assert_eq!(None, cache.get_stale("2002", PROJECTS_URL));
+    assert!(!dir.join("1001").exists());
+    assert!(dir.join("notes.txt").exists());
+    assert!(dir.join("2002/keep.json").exists());
+    assert!(dir.join("results/summary.json").exists());
+    assert!(dir.join("empty").is_dir());
+    fs::remove_dir_all(dir).unwrap();
+}